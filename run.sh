@@ -1,3 +1,3 @@
 (while true; do 
-cargo run --release -- -f fit.png -s 151.219.27.208 -p 1234
+cargo run --release -- -f fit.png -s 151.219.27.208 -p 1234 --cache-dir .frame-cache
 done) &
@@ -0,0 +1,324 @@
+//! HTTP control API for steering a running client, e.g. from a shared laptop.
+//!
+//! | Method | Path                       | Body                         |
+//! |--------|----------------------------|------------------------------|
+//! | GET    | `/status`                  |                              |
+//! | POST   | `/pause`, `/resume`        |                              |
+//! | POST   | `/offset`                  | `{"server": 0, "x": 1, "y": 2}`, `server` optional |
+//! | POST   | `/source`                  | `{"file": "fit.png"}`        |
+//! | PUT    | `/filters`                 | `[{"type": "bounce", "speed": 3}]` |
+//! | PUT    | `/filters/{index}`         | `{"type": "rainbow", "alpha": 128}` |
+//! | POST   | `/filters/{index}/enable`, `/filters/{index}/disable` | |
+//! | GET    | `/stats`                   | WebSocket upgrade, streams every stats update as JSON |
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::StatusCode,
+    response::Response,
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, watch},
+};
+
+use crate::{
+    conn::Stats,
+    control::{Control, Status},
+    frames::{bounds, SourceOptions},
+    settings::FilterSpec,
+};
+
+pub struct ApiState {
+    pub control_tx: mpsc::UnboundedSender<Control>,
+    pub status: watch::Receiver<Status>,
+    pub stats: broadcast::Sender<(usize, Arc<Stats>)>,
+    /// How new sources are opened, and the canvas of the primary server they are sized for
+    pub options: SourceOptions,
+    pub canvas_size: (u32, u32),
+}
+
+type ApiResult = Result<StatusCode, (StatusCode, String)>;
+
+#[derive(Deserialize)]
+struct OffsetRequest {
+    /// Index of the server to move the image on, all servers if missing
+    server: Option<usize>,
+    x: u32,
+    y: u32,
+}
+
+#[derive(Deserialize)]
+struct SourceRequest {
+    file: String,
+}
+
+#[derive(Serialize)]
+struct StatsMessage<'a> {
+    server: usize,
+    stats: &'a Stats,
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/offset", post(offset))
+        .route("/source", post(source))
+        .route("/filters", put(filters))
+        .route("/filters/{index}", put(filter))
+        .route("/filters/{index}/enable", post(enable))
+        .route("/filters/{index}/disable", post(disable))
+        .route("/stats", get(stats))
+        .with_state(Arc::new(state))
+}
+
+/// Serves the control API on `addr`. Fails if `addr` can't be bound, serving happens in
+/// the background.
+pub async fn serve(addr: &str, state: ApiState) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let app = router(state);
+
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    Ok(())
+}
+
+fn send(state: &ApiState, control: Control) -> ApiResult {
+    state.control_tx.send(control).map_err(|_| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The render loop stopped".to_string(),
+        )
+    })?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn not_found(what: &str) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("No such {what}"))
+}
+
+fn bad_request(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
+
+async fn status(State(state): State<Arc<ApiState>>) -> Json<Status> {
+    Json(state.status.borrow().clone())
+}
+
+async fn pause(State(state): State<Arc<ApiState>>) -> ApiResult {
+    send(&state, Control::Pause)
+}
+
+async fn resume(State(state): State<Arc<ApiState>>) -> ApiResult {
+    send(&state, Control::Resume)
+}
+
+async fn offset(
+    State(state): State<Arc<ApiState>>,
+    Json(request): Json<OffsetRequest>,
+) -> ApiResult {
+    if let Some(server) = request.server {
+        if server >= state.status.borrow().origins.len() {
+            return Err(not_found("server"));
+        }
+    }
+
+    send(
+        &state,
+        Control::Origin {
+            server: request.server,
+            x: request.x,
+            y: request.y,
+        },
+    )
+}
+
+async fn source(
+    State(state): State<Arc<ApiState>>,
+    Json(request): Json<SourceRequest>,
+) -> ApiResult {
+    let origin = state
+        .status
+        .borrow()
+        .origins
+        .first()
+        .copied()
+        .unwrap_or_default();
+    let (source, size) = state
+        .options
+        .open(&request.file, bounds(state.canvas_size, origin))
+        .await
+        .map_err(bad_request)?;
+
+    send(
+        &state,
+        Control::Source {
+            name: request.file,
+            source,
+            size,
+        },
+    )
+}
+
+async fn filters(
+    State(state): State<Arc<ApiState>>,
+    Json(specs): Json<Vec<FilterSpec>>,
+) -> ApiResult {
+    for spec in &specs {
+        spec.validate().map_err(bad_request)?;
+    }
+    send(&state, Control::Filters(specs))
+}
+
+fn check_filter(state: &ApiState, index: usize) -> Result<(), (StatusCode, String)> {
+    if index >= state.status.borrow().filters.len() {
+        return Err(not_found("filter"));
+    }
+    Ok(())
+}
+
+async fn filter(
+    State(state): State<Arc<ApiState>>,
+    Path(index): Path<usize>,
+    Json(spec): Json<FilterSpec>,
+) -> ApiResult {
+    check_filter(&state, index)?;
+    spec.validate().map_err(bad_request)?;
+    send(&state, Control::Filter { index, spec })
+}
+
+async fn enable(State(state): State<Arc<ApiState>>, Path(index): Path<usize>) -> ApiResult {
+    check_filter(&state, index)?;
+    send(
+        &state,
+        Control::EnableFilter {
+            index,
+            enabled: true,
+        },
+    )
+}
+
+async fn disable(State(state): State<Arc<ApiState>>, Path(index): Path<usize>) -> ApiResult {
+    check_filter(&state, index)?;
+    send(
+        &state,
+        Control::EnableFilter {
+            index,
+            enabled: false,
+        },
+    )
+}
+
+async fn stats(State(state): State<Arc<ApiState>>, ws: WebSocketUpgrade) -> Response {
+    let rx = state.stats.subscribe();
+    ws.on_upgrade(move |socket| stream_stats(socket, rx))
+}
+
+async fn stream_stats(mut socket: WebSocket, mut rx: broadcast::Receiver<(usize, Arc<Stats>)>) {
+    loop {
+        let (server, stats) = match rx.recv().await {
+            Ok(update) => update,
+            // missed a few updates, carry on with the next one
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let Ok(json) = serde_json::to_string(&StatsMessage {
+            server,
+            stats: &stats,
+        }) else {
+            continue;
+        };
+        if socket.send(Message::Text(json.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{control::FilterStatus, settings::FilterKind};
+
+    /// Sends a bare HTTP/1.1 request and returns the status code.
+    async fn request(addr: &str, method: &str, path: &str, body: &str) -> u16 {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response[9..12].parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_commands_to_the_render_loop() {
+        let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+        let (_status_tx, status) = watch::channel(Status {
+            file: "fit.png".to_string(),
+            origins: vec![(0, 0)],
+            filters: vec![FilterStatus {
+                spec: FilterKind::Bounce { speed: 1 }.into(),
+                enabled: true,
+            }],
+            paused: false,
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let app = router(ApiState {
+            control_tx,
+            status,
+            stats: broadcast::channel(1).0,
+            options: SourceOptions::default(),
+            canvas_size: (32, 24),
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let body = r#"{"x": 5, "y": 7}"#;
+        assert_eq!(request(&addr, "POST", "/offset", body).await, 202);
+        assert!(matches!(
+            control_rx.try_recv(),
+            Ok(Control::Origin {
+                server: None,
+                x: 5,
+                y: 7
+            })
+        ));
+
+        assert_eq!(request(&addr, "POST", "/filters/0/disable", "").await, 202);
+        assert!(matches!(
+            control_rx.try_recv(),
+            Ok(Control::EnableFilter {
+                index: 0,
+                enabled: false
+            })
+        ));
+
+        assert_eq!(request(&addr, "POST", "/filters/1/enable", "").await, 404);
+        let body = r#"[{"type": "blend", "color": "red"}]"#;
+        assert_eq!(request(&addr, "PUT", "/filters", body).await, 400);
+        assert!(control_rx.try_recv().is_err());
+    }
+}
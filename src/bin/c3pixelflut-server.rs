@@ -0,0 +1,67 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use c3pixelflut::server::{Server, ServerConfig};
+use clap::Parser;
+
+/// A local pixelflut server for rehearsing without touching the real wall
+#[derive(Parser)]
+struct Args {
+    /// The address to listen on
+    #[arg(short = 'l', long, default_value = "127.0.0.1:1337")]
+    listen: String,
+
+    /// Width of the canvas
+    #[arg(long, default_value_t = 1920)]
+    width: u32,
+
+    /// Height of the canvas
+    #[arg(long, default_value_t = 1080)]
+    height: u32,
+
+    /// Only accepts the text protocol, like servers without PB support
+    #[arg(long)]
+    text_only: bool,
+
+    /// Maximum pixels per second a single connection may set
+    #[arg(long, value_name = "PPS")]
+    rate_limit: Option<u32>,
+
+    /// Saves PNG snapshots of the canvas to <DIR>
+    #[arg(long, value_name = "DIR")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Seconds between two snapshots
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    snapshot_interval: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let server = Server::bind(
+        &args.listen,
+        ServerConfig {
+            canvas_size: (args.width, args.height),
+            binary: !args.text_only,
+            rate_limit: args.rate_limit,
+        },
+    )
+    .await?;
+
+    if let Some(dir) = args.snapshot_dir {
+        std::fs::create_dir_all(&dir)?;
+        server.snapshots(dir, Duration::from_secs(args.snapshot_interval));
+    }
+
+    println!(
+        "Serving a {}x{} canvas on {}",
+        args.width,
+        args.height,
+        server.addr()
+    );
+
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use std::{
+    fs::{self, File},
+    hash::Hasher,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use image::Rgba;
+
+use crate::{
+    edges::Edges,
+    frames::{self, Frame, Source},
+    Pixel, RESTORE_DEBUG_COLOR,
+};
+
+const MAGIC: &[u8; 8] = b"PXFRAMES";
+/// Bump whenever the layout of the cache files or the preprocessing changes.
+const VERSION: u32 = 1;
+
+/// Smallest number of bytes taken by each entry, see [`write`]
+const FRAME_SIZE: u64 = 16;
+const PIXEL_SIZE: u64 = 13;
+const RESTORE_SIZE: u64 = 8;
+const DELTA_SIZE: u64 = 4;
+
+/// Loads the preprocessed frames of `source` from the cache in `dir`, decoding and caching
+/// them if there is no entry yet.
+pub fn load_or_insert(dir: &Path, source: &Source) -> Result<(Vec<Frame>, (u32, u32))> {
+    let path = dir.join(format!("{:016x}.frames", key(source)?));
+
+    if let Ok(cached) = read(&path) {
+        if source.progress {
+            println!("Loaded {} frames from {}", cached.0.len(), path.display());
+        }
+        return Ok(cached);
+    }
+
+    let (frames, size) = frames::load(source)?;
+
+    // a broken cache must not keep us from flooding
+    if let Err(e) = write(&path, &frames, size) {
+        if source.progress {
+            println!("Failed to write frame cache {}: {e}", path.display());
+        }
+    }
+
+    Ok((frames, size))
+}
+
+/// 64 bit FNV-1a. Unlike the std hashers its output is fixed, so cache entries stay valid
+/// across Rust releases.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hashes the contents of the source file together with every option that affects
+/// preprocessing.
+fn key(source: &Source) -> Result<u64> {
+    let mut hasher = Fnv1a::default();
+    hasher.write(&VERSION.to_le_bytes());
+    hasher.write_u8(source.restore as u8);
+    let filtergraph = source.filtergraph.as_deref().unwrap_or_default();
+    hasher.write_u8(source.filtergraph.is_some() as u8);
+    hasher.write(&(filtergraph.len() as u64).to_le_bytes());
+    hasher.write(filtergraph.as_bytes());
+
+    let mut reader = BufReader::new(File::open(&source.file)?);
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buf[..read]);
+    }
+
+    Ok(hasher.finish())
+}
+
+fn write(path: &Path, frames: &[Frame], size: (u32, u32)) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // write to a temporary file first, so an interrupted run can't leave a truncated entry
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&size.0.to_le_bytes())?;
+    out.write_all(&size.1.to_le_bytes())?;
+    out.write_all(&(frames.len() as u32).to_le_bytes())?;
+
+    for frame in frames {
+        out.write_all(&frame.timestamp.to_le_bytes())?;
+
+        out.write_all(&(frame.pixels.len() as u32).to_le_bytes())?;
+        for px in frame.pixels.iter() {
+            out.write_all(&px.x.to_le_bytes())?;
+            out.write_all(&px.y.to_le_bytes())?;
+            out.write_all(&px.value.0)?;
+            out.write_all(&[px.edges.bits()])?;
+        }
+
+        // restore pixels only carry coordinates
+        out.write_all(&(frame.restore.len() as u32).to_le_bytes())?;
+        for px in frame.restore.iter() {
+            out.write_all(&px.x.to_le_bytes())?;
+            out.write_all(&px.y.to_le_bytes())?;
+        }
+
+        out.write_all(&(frame.delta.len() as u32).to_le_bytes())?;
+        for i in frame.delta.iter() {
+            out.write_all(&(*i as u32).to_le_bytes())?;
+        }
+    }
+
+    out.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<(Vec<Frame>, (u32, u32))> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut input = BufReader::new(file);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut input)? != VERSION {
+        return Err(anyhow!("Unknown frame cache format"));
+    }
+
+    let size = (read_u32(&mut input)?, read_u32(&mut input)?);
+
+    let num_frames = read_count(&mut input, len, FRAME_SIZE)?;
+    let mut frames = Vec::with_capacity(num_frames);
+    for _ in 0..num_frames {
+        let timestamp = f32::from_bits(read_u32(&mut input)?);
+
+        let num_pixels = read_count(&mut input, len, PIXEL_SIZE)?;
+        let mut pixels = Vec::with_capacity(num_pixels);
+        for _ in 0..num_pixels {
+            let x = read_u32(&mut input)?;
+            let y = read_u32(&mut input)?;
+            let mut value = [0; 5];
+            input.read_exact(&mut value)?;
+            pixels.push(Pixel {
+                x,
+                y,
+                value: Rgba::from([value[0], value[1], value[2], value[3]]),
+                edges: Edges::from_bits(value[4]),
+            });
+        }
+
+        let num_restore = read_count(&mut input, len, RESTORE_SIZE)?;
+        let mut restore = Vec::with_capacity(num_restore);
+        for _ in 0..num_restore {
+            restore.push(Pixel {
+                x: read_u32(&mut input)?,
+                y: read_u32(&mut input)?,
+                value: Rgba::from(RESTORE_DEBUG_COLOR),
+                edges: Edges::default(),
+            });
+        }
+
+        let num_delta = read_count(&mut input, len, DELTA_SIZE)?;
+        let mut delta = Vec::with_capacity(num_delta);
+        for _ in 0..num_delta {
+            delta.push(read_u32(&mut input)? as usize);
+        }
+
+        frames.push(Frame {
+            timestamp,
+            pixels,
+            restore,
+            delta,
+        });
+    }
+
+    Ok((frames, size))
+}
+
+/// Reads the number of items that follow, each taking at least `item_size` bytes. Counts
+/// that can't fit into the `len` bytes of the file are rejected before anything is
+/// allocated for them.
+fn read_count(input: &mut impl Read, len: u64, item_size: u64) -> Result<usize> {
+    let count = read_u32(input)?;
+    if count as u64 * item_size > len {
+        return Err(anyhow!("Corrupt frame cache"));
+    }
+    Ok(count as usize)
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(x: u32, y: u32, value: [u8; 4]) -> Pixel {
+        Pixel {
+            x,
+            y,
+            value: Rgba(value),
+            edges: Edges::from_bits(0b101),
+        }
+    }
+
+    #[test]
+    fn reads_what_it_wrote() {
+        let dir = std::env::temp_dir().join(format!("c3pixelflut-cache-{}", std::process::id()));
+        let path = dir.join("test.frames");
+        let frames = vec![
+            Frame {
+                timestamp: 0.0,
+                pixels: vec![pixel(1, 2, [1, 2, 3, 4]), pixel(3, 4, [5, 6, 7, 8])],
+                restore: vec![pixel(5, 6, [0; 4])],
+                delta: vec![1],
+            },
+            Frame {
+                timestamp: 0.04,
+                pixels: Vec::new(),
+                restore: Vec::new(),
+                delta: Vec::new(),
+            },
+        ];
+
+        write(&path, &frames, (32, 24)).unwrap();
+        let (cached, size) = read(&path).unwrap();
+        assert_eq!(size, (32, 24));
+        assert_eq!(cached.len(), 2);
+        assert_eq!(cached[1].timestamp, 0.04);
+
+        let pixels: Vec<_> = cached[0]
+            .pixels
+            .iter()
+            .map(|px| (px.x, px.y, px.value, px.edges.bits()))
+            .collect();
+        assert_eq!(
+            pixels,
+            [
+                (1, 2, Rgba([1, 2, 3, 4]), 0b101),
+                (3, 4, Rgba([5, 6, 7, 8]), 0b101)
+            ]
+        );
+        let restore: Vec<_> = cached[0].restore.iter().map(|px| (px.x, px.y)).collect();
+        assert_eq!(restore, [(5, 6)]);
+        assert_eq!(cached[0].delta, [1]);
+
+        // a count larger than the file is caught before allocating
+        let mut data = fs::read(&path).unwrap();
+        data[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, data).unwrap();
+        assert!(read(&path).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_the_same_on_every_build() {
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -1,45 +1,53 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
-use crate::{Config, Pixel};
+use crate::{edges::Edges, limit::RateLimiter, shadow::Shadow, Config, Pixel};
 use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use image::Rgba;
+use rand::random_range;
+use serde::Serialize;
 use tokio::{
-    io::AsyncWriteExt,
-    net::TcpStream,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
     sync::{mpsc, oneshot},
     task::JoinSet,
+    time::{sleep, timeout, Instant},
 };
 
-async fn connection(
-    server: String,
-    conn_id: usize,
-    num_conns: usize,
-) -> Result<mpsc::UnboundedSender<(Arc<Vec<Pixel>>, oneshot::Sender<usize>)>> {
-    let (tx, mut rx) = mpsc::unbounded_channel();
-
-    tokio::spawn(async move {
-        let mut tcp_tx = TcpStream::connect(&server).await.unwrap().into_split().1;
-
-        loop {
-            let (buffer, oneshot_tx): (Arc<Vec<Pixel>>, oneshot::Sender<usize>) =
-                rx.recv().await.unwrap();
-            let mut errors = 0;
-
-            let mut num_px = buffer.len() / num_conns;
-
-            if buffer.len() % num_conns > conn_id {
-                num_px += 1;
-            }
+/// Upper bound for the amount of bytes handed to a single write call.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
 
-            for i in 0..num_px {
-                let idx = (i * num_conns) + conn_id;
+/// Time to wait for the next answer of a pixel read before giving up on the connection.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
 
-                // if idx % 128 == 0 {
-                //     tokio::task::yield_now().await;
-                // }
+/// The wire format used for setting pixels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Protocol {
+    /// `PX <x> <y> <rrggbbaa>\n`
+    #[default]
+    Text,
+    /// `PB` followed by little endian u16 x and y and the raw RGBA bytes
+    Binary,
+}
 
-                let px = &buffer[idx];
-                let command = format!(
-                    "PX {x} {y} {r:02x}{g:02x}{b:02x}{a:02x}\n",
+impl Protocol {
+    /// Appends the command setting `px` to `out`.
+    pub fn encode(&self, px: &Pixel, out: &mut Vec<u8>) {
+        match self {
+            Protocol::Text => {
+                // writing into a Vec can't fail
+                let _ = writeln!(
+                    out,
+                    "PX {x} {y} {r:02x}{g:02x}{b:02x}{a:02x}",
                     x = px.x,
                     y = px.y,
                     r = px.value[0],
@@ -47,108 +55,1431 @@ async fn connection(
                     b = px.value[2],
                     a = px.value[3]
                 );
+            }
+            Protocol::Binary => {
+                out.extend_from_slice(b"PB");
+                out.extend_from_slice(&(px.x as u16).to_le_bytes());
+                out.extend_from_slice(&(px.y as u16).to_le_bytes());
+                out.extend_from_slice(&px.value.0);
+            }
+        }
+    }
 
-                loop {
-                    match tcp_tx.write(command.as_bytes()).await {
-                        Err(_e) => {
-                            // println!("Error: {e}");
-                            errors += 1;
-                            tcp_tx = TcpStream::connect(&server).await.unwrap().into_split().1;
+    /// Parses the 8 bytes following `PB`, the inverse of encoding with [`Protocol::Binary`].
+    pub fn decode_binary(command: [u8; 8]) -> Pixel {
+        Pixel {
+            x: u16::from_le_bytes([command[0], command[1]]) as u32,
+            y: u16::from_le_bytes([command[2], command[3]]) as u32,
+            value: Rgba([command[4], command[5], command[6], command[7]]),
+            edges: Edges::default(),
+        }
+    }
+
+    /// The average size of a single encoded command in bytes.
+    fn command_size(&self) -> usize {
+        match self {
+            Protocol::Text => 20,
+            Protocol::Binary => 10,
+        }
+    }
+}
+
+/// How the pixels of a frame are split across the connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Partition {
+    /// Every connection sends every n-th pixel of the whole image
+    #[default]
+    Interleave,
+    /// Every connection owns a rectangular tile of the image
+    Tiles,
+}
+
+type ConnectionTx = mpsc::UnboundedSender<ConnectionJob>;
+
+enum ConnectionJob {
+    Draw {
+        commands: Arc<CommandBuffer>,
+        done: oneshot::Sender<ConnectionStats>,
+    },
+    Read {
+        queries: Arc<CommandBuffer>,
+        done: oneshot::Sender<(Vec<Pixel>, ConnectionStats)>,
+    },
+}
+
+/// The encoded commands one connection sends for a frame.
+///
+/// Encoding happens once per frame, the buffer is then re-sent on every redraw.
+#[derive(Default)]
+pub struct CommandBuffer {
+    /// Commands that set up connection state, sent again after every reconnect.
+    preamble: Vec<u8>,
+    data: Vec<u8>,
+    /// End offsets of the write chunks and the number of commands up to there. These
+    /// always lie on command boundaries, so a chunk can be re-sent as a whole on a
+    /// fresh connection.
+    chunks: Vec<(usize, usize)>,
+    /// Number of commands in `data`.
+    len: usize,
+}
+
+impl CommandBuffer {
+    /// Encodes `pixels`, which are moved by `offset`, either through a single OFFSET
+    /// command in the preamble or by adding it to every coordinate. Pixels ending up at
+    /// negative coordinates are dropped.
+    ///
+    /// With the OFFSET command, coordinates are sent relative to `origin`, keeping them
+    /// short when all pixels lie in a small region.
+    fn encode<'a>(
+        pixels: impl Iterator<Item = &'a Pixel>,
+        protocol: Protocol,
+        offset: (i32, i32),
+        offset_command: bool,
+        origin: (i32, i32),
+    ) -> Self {
+        let mut commands = Self {
+            data: Vec::with_capacity(pixels.size_hint().0 * protocol.command_size()),
+            ..Default::default()
+        };
+
+        let mut shift = offset;
+        if offset_command {
+            let (x, y) = (offset.0 + origin.0, offset.1 + origin.1);
+            // the server has no notion of negative offsets
+            let (x, y) = if x >= 0 && y >= 0 {
+                shift = (-origin.0, -origin.1);
+                (x, y)
+            } else {
+                (0, 0)
+            };
+            let _ = writeln!(commands.preamble, "OFFSET {x} {y}");
+        }
+
+        for px in pixels {
+            let Some((x, y)) = absolute(px, shift) else {
+                continue;
+            };
+
+            let px = Pixel { x, y, ..*px };
+            protocol.encode(&px, &mut commands.data);
+            commands.end_command();
+        }
+
+        commands.finish()
+    }
+
+    /// Encodes `PX <x> <y>` queries for every `num_conns`th coordinate, starting at `conn_id`.
+    pub(crate) fn encode_reads(
+        coords: &[(u32, u32)],
+        conn_id: usize,
+        num_conns: usize,
+        offset_command: bool,
+    ) -> Self {
+        let mut queries = Self::default();
+
+        if offset_command {
+            let _ = writeln!(queries.preamble, "OFFSET 0 0");
+        }
+
+        for (x, y) in coords.iter().skip(conn_id).step_by(num_conns) {
+            let _ = writeln!(queries.data, "PX {x} {y}");
+            queries.end_command();
+        }
+
+        queries.finish()
+    }
+
+    fn end_command(&mut self) {
+        self.len += 1;
+        if self.data.len() - self.chunk_start().0 >= WRITE_CHUNK_SIZE {
+            self.chunks.push((self.data.len(), self.len));
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        if self.data.len() > self.chunk_start().0 {
+            self.chunks.push((self.data.len(), self.len));
+        }
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn chunk_start(&self) -> (usize, usize) {
+        self.chunks.last().copied().unwrap_or_default()
+    }
+
+    /// The write chunks together with the number of commands they contain.
+    fn chunks(&self) -> impl Iterator<Item = (&[u8], usize)> {
+        let starts = std::iter::once((0, 0)).chain(self.chunks.iter().copied());
+        starts
+            .zip(self.chunks.iter().copied())
+            .map(|((start, start_len), (end, end_len))| {
+                (&self.data[start..end], end_len - start_len)
+            })
+    }
+}
+
+/// Canvas coordinates of `px` moved by `offset`, `None` if they end up negative.
+///
+/// Buffer coordinates left of or above the image are stored wrapped around, casting
+/// them to `i32` turns them back into negative values.
+fn absolute(px: &Pixel, offset: (i32, i32)) -> Option<(u32, u32)> {
+    let (x, y) = (px.x as i32 + offset.0, px.y as i32 + offset.1);
+    if x < 0 || y < 0 {
+        None
+    } else {
+        Some((x as u32, y as u32))
+    }
+}
+
+/// `offset` limited so that filters can't move the image past the edges of the canvas.
+/// Filters only know the canvas of the primary server, other servers may be smaller.
+fn clamp_offset(offset: (i32, i32), config: &Config) -> (i32, i32) {
+    let area = &config.image_area;
+    let clamp = |offset: i32, origin: u32, size: u32, canvas: u32| {
+        let max = (canvas as i64 - origin as i64 - size as i64).max(0) as i32;
+        offset.clamp(-(origin as i32), max)
+    };
+    (
+        clamp(offset.0, area.origin_x, area.size_x, config.canvas_size.0),
+        clamp(offset.1, area.origin_y, area.size_y, config.canvas_size.1),
+    )
+}
+
+pub(crate) async fn connect(server: &str) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf)> {
+    let (rx, tx) = TcpStream::connect(server).await?.into_split();
+    Ok((BufReader::new(rx), tx))
+}
+
+/// Sends `queries` and collects the answers into `pixels`, reading while writing so that
+/// neither side's socket buffers can fill up. On failure `pixels` keeps the answers that
+/// arrived up to then.
+pub(crate) async fn read_pixels(
+    rx: &mut BufReader<OwnedReadHalf>,
+    tx: &mut OwnedWriteHalf,
+    queries: &CommandBuffer,
+    pixels: &mut Vec<Pixel>,
+    limiter: &RateLimiter,
+) -> Result<()> {
+    let write = async {
+        tx.write_all(&queries.preamble).await?;
+        for (chunk, count) in queries.chunks() {
+            // queries cost as much uplink as setting the pixels
+            limiter.acquire(count, chunk.len()).await;
+            tx.write_all(chunk).await?;
+        }
+        Ok(())
+    };
+
+    let read = async {
+        pixels.reserve(queries.len);
+        let mut line = String::new();
+        for _ in 0..queries.len {
+            line.clear();
+            if timeout(READ_TIMEOUT, rx.read_line(&mut line)).await?? == 0 {
+                return Err(anyhow!("Server closed the connection while reading pixels"));
+            }
+
+            if let Some(px) = parse_pixel(&line) {
+                pixels.push(px);
+            }
+        }
+        Ok(())
+    };
+
+    tokio::try_join!(write, read)?;
+    Ok(())
+}
+
+/// Parses a `PX <x> <y> <rrggbb[aa]>` command or answer.
+pub fn parse_pixel(line: &str) -> Option<Pixel> {
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "PX" {
+        return None;
+    }
+
+    let x = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+
+    let color = parts.next()?;
+    let value = u32::from_str_radix(color, 16).ok()?;
+    let value = match color.len() {
+        6 => (value << 8) | 0xff,
+        8 => value,
+        _ => return None,
+    };
+
+    Some(Pixel {
+        x,
+        y,
+        value: Rgba::from(value.to_be_bytes()),
+        edges: Edges::default(),
+    })
+}
+
+/// A server to flood, written as `<address>[:<port>][,threads=<n>][,x=<px>][,y=<px>]`.
+///
+/// Everything that isn't given falls back to the global options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub address: String,
+    pub port: Option<u16>,
+    pub threads: Option<usize>,
+    pub offset_x: Option<u32>,
+    pub offset_y: Option<u32>,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let server = parts.next().unwrap_or_default();
+        let (address, port) = match server.rsplit_once(':') {
+            Some((address, port)) => (
+                address,
+                Some(port.parse().map_err(|_| format!("invalid port '{port}'"))?),
+            ),
+            None => (server, None),
+        };
+        if address.is_empty() {
+            return Err(format!("missing address in '{s}'"));
+        }
+
+        let mut target = Self {
+            address: address.to_string(),
+            port,
+            threads: None,
+            offset_x: None,
+            offset_y: None,
+        };
+        for option in parts {
+            let err =
+                || format!("invalid option '{option}', expected threads=<n>, x=<px> or y=<px>");
+            let (key, value) = option.split_once('=').ok_or_else(err)?;
+            match key {
+                "threads" => target.threads = Some(value.parse().map_err(|_| err())?),
+                "x" => target.offset_x = Some(value.parse().map_err(|_| err())?),
+                "y" => target.offset_y = Some(value.parse().map_err(|_| err())?),
+                _ => return Err(err()),
+            }
+        }
+
+        Ok(target)
+    }
+}
+
+/// How connections are re-established after errors.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delay before the first retry, doubled with every further failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive failed attempts after which a connection is given up, `None` retries forever
+    pub max_retries: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// The delay before the `attempt`th retry, randomized by up to half of it so that
+    /// connections don't hit the server in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff);
+        backoff.mul_f64(random_range(0.5..=1.0))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    /// Waiting before the next connection attempt
+    Backoff,
+    /// Gave up after too many failed attempts
+    Failed,
+}
+
+/// What a single connection did during one job.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConnectionStats {
+    pub state: ConnectionState,
+    pub errors: usize,
+    pub reconnects: usize,
+    pub pixels: usize,
+    pub bytes: usize,
+    /// Duration of every chunk write
+    pub write_latencies: Vec<Duration>,
+}
+
+/// A connection to the server that is re-established on demand.
+struct Link {
+    server: String,
+    policy: ReconnectPolicy,
+    socket: Option<(BufReader<OwnedReadHalf>, OwnedWriteHalf)>,
+    state: ConnectionState,
+    /// Successful connects after the first one, since the last report
+    reconnects: usize,
+    connected_before: bool,
+}
+
+impl Link {
+    fn new(server: String, policy: ReconnectPolicy) -> Self {
+        Self {
+            server,
+            policy,
+            socket: None,
+            state: ConnectionState::Connecting,
+            reconnects: 0,
+            connected_before: false,
+        }
+    }
+
+    /// The current socket, connecting with backoff if there is none.
+    /// Fails once the link gave up.
+    async fn socket(&mut self) -> Result<&mut (BufReader<OwnedReadHalf>, OwnedWriteHalf)> {
+        let mut attempt = 0;
+        while self.socket.is_none() {
+            if self.state == ConnectionState::Failed {
+                return Err(anyhow!("Gave up connecting to {}", self.server));
+            }
+
+            self.state = ConnectionState::Connecting;
+            match connect(&self.server).await {
+                Ok(socket) => {
+                    self.socket = Some(socket);
+                    self.state = ConnectionState::Connected;
+                    if self.connected_before {
+                        self.reconnects += 1;
+                    }
+                    self.connected_before = true;
+                }
+                Err(_e) => {
+                    attempt += 1;
+                    if self
+                        .policy
+                        .max_retries
+                        .is_some_and(|max_retries| attempt > max_retries)
+                    {
+                        self.state = ConnectionState::Failed;
+                    } else {
+                        self.state = ConnectionState::Backoff;
+                        sleep(self.policy.backoff(attempt - 1)).await;
+                    }
+                }
+            }
+        }
+
+        Ok(self.socket.as_mut().unwrap())
+    }
+
+    fn failed(&self) -> bool {
+        self.state == ConnectionState::Failed
+    }
+
+    /// Drops the socket after an error, the next job reconnects.
+    fn disconnect(&mut self) {
+        self.socket = None;
+        if !self.failed() {
+            self.state = ConnectionState::Connecting;
+        }
+    }
+
+    /// Completes `stats` with the link's state and reconnects since the last report.
+    fn report(&mut self, stats: ConnectionStats) -> ConnectionStats {
+        ConnectionStats {
+            state: self.state,
+            reconnects: std::mem::take(&mut self.reconnects),
+            ..stats
+        }
+    }
+}
+
+fn connection(server: String, policy: ReconnectPolicy, limiter: Arc<RateLimiter>) -> ConnectionTx {
+    let (tx, mut rx): (ConnectionTx, _) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut link = Link::new(server, policy);
+
+        while let Some(job) = rx.recv().await {
+            match job {
+                ConnectionJob::Draw { commands, done } => {
+                    let mut stats = ConnectionStats::default();
+                    let mut needs_preamble = true;
+                    let failed_before = link.failed();
+
+                    'chunks: for (chunk, pixels) in commands.chunks() {
+                        loop {
+                            let Ok((_, tcp_tx)) = link.socket().await else {
+                                // a dead connection only costs its share of the pixels,
+                                // and counts as an error once when it gives up
+                                if !failed_before {
+                                    stats.errors += 1;
+                                }
+                                break 'chunks;
+                            };
+
+                            let mut bytes = chunk.len();
+                            if needs_preamble {
+                                bytes += commands.preamble.len();
+                            }
+                            limiter.acquire(pixels, bytes).await;
+
+                            let start = Instant::now();
+                            let mut res = Ok(());
+                            if needs_preamble {
+                                res = tcp_tx.write_all(&commands.preamble).await;
+                            }
+                            if res.is_ok() {
+                                res = tcp_tx.write_all(chunk).await;
+                            }
+
+                            match res {
+                                Err(_e) => {
+                                    // println!("Error: {e}");
+                                    stats.errors += 1;
+                                    link.disconnect();
+                                    needs_preamble = true;
+                                }
+                                Ok(_) => {
+                                    stats.write_latencies.push(start.elapsed());
+                                    stats.pixels += pixels;
+                                    stats.bytes += bytes;
+                                    needs_preamble = false;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    let _ = done.send(link.report(stats));
+                }
+                ConnectionJob::Read { queries, done } => {
+                    let mut pixels = Vec::new();
+                    let failed_before = link.failed();
+                    let res = match link.socket().await {
+                        Ok((tcp_rx, tcp_tx)) => {
+                            read_pixels(tcp_rx, tcp_tx, &queries, &mut pixels, &limiter).await
                         }
-                        Ok(_) => break,
+                        Err(e) => Err(e),
                     };
+
+                    let mut stats = ConnectionStats::default();
+                    if res.is_err() {
+                        // answers of the failed read may still be in flight, the ones
+                        // that made it are kept
+                        link.disconnect();
+                        if !failed_before {
+                            stats.errors = 1;
+                        }
+                    }
+                    let _ = done.send((pixels, link.report(stats)));
                 }
             }
-            oneshot_tx.send(errors).unwrap();
         }
     });
 
-    Ok(tx)
+    tx
 }
 
 pub struct ConnectionBundle {
     tx: mpsc::UnboundedSender<Job>,
+    /// Why the bundle stopped, set once it did
+    error: Arc<OnceLock<String>>,
 }
 
 pub enum Job {
     UpdateBuffer {
         buffer: Vec<Pixel>,
         restore: Option<Vec<Pixel>>,
+        /// Indices of the pixels that changed since the previous buffer, sent first
+        delta: Option<Vec<usize>>,
+        /// Translation of the image relative to its origin
+        offset: (i32, i32),
     },
+    /// Moves the image to a new origin on the canvas, starting with the next buffer
+    SetOrigin {
+        x: u32,
+        y: u32,
+    },
+    /// Stops sending until [`Job::Resume`], buffers arriving in the meantime are held back
+    Pause,
+    Resume,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Serialize)]
 pub struct Stats {
     pub errors: usize,
+    pub reconnects: usize,
+    pub pixels: usize,
+    pub bytes: usize,
+    /// Completed passes over the whole frame buffer
+    pub frames: usize,
+    /// Per connection stats, indexed by connection id
+    pub connections: Vec<ConnectionStats>,
+}
+
+impl Stats {
+    fn new(num_conns: usize) -> Self {
+        Self {
+            connections: vec![ConnectionStats::default(); num_conns],
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, conn_id: usize, stats: ConnectionStats) {
+        self.errors += stats.errors;
+        self.reconnects += stats.reconnects;
+        self.pixels += stats.pixels;
+        self.bytes += stats.bytes;
+        self.connections[conn_id] = stats;
+    }
 }
 
 impl ConnectionBundle {
-    pub async fn new(config: Config, stats_tx: mpsc::UnboundedSender<Stats>) -> Result<Self> {
+    pub async fn new(
+        config: Config,
+        stats_tx: mpsc::UnboundedSender<Stats>,
+        shadow: Option<Shadow>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<Self> {
         let (mpsc_tx, mut mpsc_rx) = mpsc::unbounded_channel();
+        let error = Arc::new(OnceLock::new());
 
+        let stopped = error.clone();
         tokio::spawn(async move {
-            let mut buffer: Arc<Vec<Pixel>> = Arc::new(Vec::new());
-            let mut restore: Option<Vec<Pixel>> = None;
-
-            let mut connections = Vec::with_capacity(config.threads);
-            for i in 0..config.threads {
-                connections.push(
-                    connection(config.server.clone(), i, config.threads)
-                        .await
-                        .unwrap(),
-                );
-            }
+            let res: Result<()> = async {
+                let mut painter = Painter::new(config, stats_tx, shadow, limiter).await?;
+
+                let mut paused = false;
+                // the latest buffer that arrived while paused
+                let mut pending = None;
 
-            loop {
-                if !mpsc_rx.is_empty() || buffer.len() == 0 {
-                    match mpsc_rx.recv().await.unwrap() {
-                        Job::UpdateBuffer {
-                            buffer: new_buffer,
-                            restore: new_restore,
-                        } => {
-                            if let Some(restore) = restore {
-                                draw(&mut connections, &Arc::new(restore), stats_tx.clone())
-                                    .await
-                                    .unwrap();
+                loop {
+                    if paused || !mpsc_rx.is_empty() || painter.is_idle() {
+                        let job = match painter.defend.as_mut() {
+                            // nothing to repair, wait until someone paints over us or a new frame arrives
+                            Some(shadow) if !paused && !painter.target.is_empty() => {
+                                tokio::select! {
+                                    job = mpsc_rx.recv() => job,
+                                    _ = shadow.changed() => None,
+                                }
+                            }
+                            _ => mpsc_rx.recv().await,
+                        };
+
+                        let job = match job {
+                            Some(Job::Pause) => {
+                                paused = true;
+                                continue;
+                            }
+                            Some(Job::Resume) => {
+                                paused = false;
+                                pending.take()
+                            }
+                            Some(job @ Job::UpdateBuffer { .. }) if paused => {
+                                pending = Some(job);
+                                continue;
                             }
-                            restore = new_restore;
-                            // TODO: fetch restore pixels
+                            job => job,
+                        };
 
-                            buffer = Arc::new(new_buffer);
+                        match job {
+                            Some(Job::UpdateBuffer {
+                                buffer,
+                                restore,
+                                delta,
+                                offset,
+                            }) => {
+                                painter
+                                    .update_buffer(buffer, restore, delta, offset)
+                                    .await?
+                            }
+                            Some(Job::SetOrigin { x, y }) => {
+                                painter.config.image_area.origin_x = x;
+                                painter.config.image_area.origin_y = y;
+                            }
+                            Some(Job::Pause | Job::Resume) | None => (),
                         }
                     }
+
+                    if paused {
+                        continue;
+                    }
+                    painter.repair();
+                    painter.draw().await?;
                 }
+            }
+            .await;
 
-                draw(&mut connections, &buffer, stats_tx.clone())
-                    .await
-                    .unwrap();
+            // set before the job channel closes with the end of this task
+            if let Err(e) = res {
+                let _ = stopped.set(e.to_string());
             }
         });
 
-        Ok(Self { tx: mpsc_tx })
+        Ok(Self { tx: mpsc_tx, error })
     }
 
-    pub fn update_buffer(&self, buffer: Vec<Pixel>, restore: Option<Vec<Pixel>>) -> Result<()> {
-        self.tx.send(Job::UpdateBuffer { buffer, restore })?;
-        Ok(())
+    fn send(&self, job: Job) -> Result<()> {
+        self.tx.send(job).map_err(|_| {
+            let reason = self.error.get().map_or("unknown error", String::as_str);
+            anyhow!("Stopped flooding: {reason}")
+        })
+    }
+
+    pub fn update_buffer(
+        &self,
+        buffer: Vec<Pixel>,
+        restore: Option<Vec<Pixel>>,
+        delta: Option<Vec<usize>>,
+        offset: (i32, i32),
+    ) -> Result<()> {
+        self.send(Job::UpdateBuffer {
+            buffer,
+            restore,
+            delta,
+            offset,
+        })
+    }
+
+    pub fn set_origin(&self, x: u32, y: u32) -> Result<()> {
+        self.send(Job::SetOrigin { x, y })
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        self.send(Job::Pause)
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.send(Job::Resume)
     }
 }
 
-async fn draw(
-    connections: &mut Vec<mpsc::UnboundedSender<(Arc<Vec<Pixel>>, oneshot::Sender<usize>)>>,
-    buffer: &Arc<Vec<Pixel>>,
+/// State of the render loop driving the connections of a bundle.
+struct Painter {
+    config: Config,
     stats_tx: mpsc::UnboundedSender<Stats>,
-) -> Result<()> {
+    connections: Vec<ConnectionTx>,
+    commands: Vec<Arc<CommandBuffer>>,
+    restore: Option<Vec<Pixel>>,
+    /// Colors of the canvas under the pixels we currently cover
+    background: HashMap<(u32, u32), Rgba<u8>>,
+    /// Pixels we restored and the shadow generation at that time. Snapshots taken before
+    /// may still show our own pixels there.
+    released: HashMap<(u32, u32), u64>,
+    shadow: Option<Shadow>,
+    /// The shadow driving defend mode
+    defend: Option<Shadow>,
+    /// The current frame in canvas coordinates, only kept in defend mode
+    target: Vec<Pixel>,
+    /// Snapshot generation the current commands were computed from
+    generation: u64,
+}
+
+impl Painter {
+    async fn new(
+        config: Config,
+        stats_tx: mpsc::UnboundedSender<Stats>,
+        shadow: Option<Shadow>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<Self> {
+        let connections = (0..config.threads)
+            .map(|_| {
+                connection(
+                    config.server.clone(),
+                    config.reconnect.clone(),
+                    limiter.clone(),
+                )
+            })
+            .collect();
+
+        let defend = if config.defend { shadow.clone() } else { None };
+
+        Ok(Self {
+            config,
+            stats_tx,
+            connections,
+            commands: Vec::new(),
+            restore: None,
+            background: HashMap::new(),
+            released: HashMap::new(),
+            shadow,
+            defend,
+            target: Vec::new(),
+            generation: 0,
+        })
+    }
+
+    fn is_idle(&self) -> bool {
+        self.commands.iter().all(|c| c.is_empty())
+    }
+
+    async fn update_buffer(
+        &mut self,
+        buffer: Vec<Pixel>,
+        restore: Option<Vec<Pixel>>,
+        delta: Option<Vec<usize>>,
+        offset: (i32, i32),
+    ) -> Result<()> {
+        let offset = clamp_offset(offset, &self.config);
+        let offset = (
+            self.config.image_area.origin_x as i32 + offset.0,
+            self.config.image_area.origin_y as i32 + offset.1,
+        );
+
+        if self.config.restore {
+            self.swap_restore(&buffer, restore, offset).await?;
+        }
+
+        if self.defend.is_some() {
+            self.target = buffer
+                .iter()
+                .filter_map(|px| {
+                    let (x, y) = absolute(px, offset)?;
+                    Some(Pixel { x, y, ..*px })
+                })
+                .collect();
+            self.config.order.apply(&mut self.target);
+            // force a damage check against the new frame
+            self.generation = u64::MAX;
+        } else {
+            // get the changed pixels out quickly, the full buffer trickles in afterwards
+            if let Some(delta) = delta {
+                let mut delta: Vec<Pixel> = delta
+                    .into_iter()
+                    .filter_map(|i| buffer.get(i).copied())
+                    .collect();
+                self.config.order.apply(&mut delta);
+                let delta = encode(&delta, &self.config, offset);
+                let stats = draw(&self.connections, &delta).await?;
+                self.report(stats)?;
+            }
+
+            // reordering has to wait until the delta is picked out by index
+            let mut buffer = buffer;
+            self.config.order.apply(&mut buffer);
+            self.commands = encode(&buffer, &self.config, offset);
+        }
+
+        Ok(())
+    }
+
+    /// Restores the pixels the previous frame no longer covers and records what the
+    /// canvas looks like under `buffer` before it gets drawn.
+    async fn swap_restore(
+        &mut self,
+        buffer: &[Pixel],
+        restore: Option<Vec<Pixel>>,
+        offset: (i32, i32),
+    ) -> Result<()> {
+        // pixels off the canvas are never answered when read
+        let (width, height) = self.config.canvas_size;
+        let covered: HashSet<(u32, u32)> = buffer
+            .iter()
+            .filter_map(|px| absolute(px, offset))
+            .filter(|&(x, y)| x < width && y < height)
+            .collect();
+
+        // remember what the wall looks like before covering new pixels,
+        // taken from the shadow where possible
+        let mut unknown: Vec<(u32, u32)> = covered
+            .iter()
+            .filter(|coords| !self.background.contains_key(coords))
+            .copied()
+            .collect();
+        if let Some(shadow) = &self.shadow {
+            // taken before the read, so the snapshot is at least this recent
+            let generation = shadow.generation();
+            if let Some(snapshot) = shadow.read() {
+                unknown.retain(|&(x, y)| {
+                    // the snapshot right after the restore may have been started before it
+                    let stale = self
+                        .released
+                        .get(&(x, y))
+                        .is_some_and(|&released| generation < released + 2);
+                    match snapshot.get(x, y) {
+                        Some(value) if !stale => {
+                            self.background.insert((x, y), value);
+                            false
+                        }
+                        _ => true,
+                    }
+                });
+            }
+        }
+        if !unknown.is_empty() {
+            let (pixels, stats) = read(&self.connections, &unknown, &self.config).await?;
+            self.report(stats)?;
+            for px in pixels {
+                self.background.insert((px.x, px.y), px.value);
+            }
+        }
+        for coords in &covered {
+            self.released.remove(coords);
+        }
+
+        if let Some(restore) = self.restore.take() {
+            let restore: Vec<Pixel> = restore
+                .into_iter()
+                .filter(|px| !covered.contains(&(px.x, px.y)))
+                .filter_map(|px| {
+                    let value = self.background.remove(&(px.x, px.y))?;
+                    Some(Pixel { value, ..px })
+                })
+                .collect();
+            let commands = encode(&restore, &self.config, (0, 0));
+            let stats = draw(&self.connections, &commands).await?;
+            self.report(stats)?;
+
+            if let Some(shadow) = &self.shadow {
+                let generation = shadow.generation();
+                self.released
+                    .extend(restore.iter().map(|px| ((px.x, px.y), generation)));
+            }
+        }
+
+        // restore sets are kept in canvas coordinates
+        self.restore = restore.map(|buf| {
+            buf.iter()
+                .filter_map(|px| {
+                    let (x, y) = absolute(px, offset)?;
+                    Some(Pixel { x, y, ..*px })
+                })
+                .collect()
+        });
+
+        Ok(())
+    }
+
+    /// In defend mode, narrows the commands down to the pixels that differ on the
+    /// latest snapshot whenever a new one is available.
+    fn repair(&mut self) {
+        let Some(shadow) = &self.defend else {
+            return;
+        };
+
+        let generation = shadow.generation();
+        if generation == self.generation {
+            return;
+        }
+        self.generation = generation;
+
+        let damaged = shadow.damaged(&self.target);
+        self.commands = encode(&damaged, &self.config, (0, 0));
+    }
+
+    /// Draws one pass over the current commands.
+    async fn draw(&self) -> Result<()> {
+        let mut stats = draw(&self.connections, &self.commands).await?;
+        stats.frames = 1;
+        let gave_up = stats
+            .connections
+            .iter()
+            .all(|conn| conn.state == ConnectionState::Failed);
+        self.report(stats)?;
+
+        if gave_up {
+            return Err(anyhow!(
+                "Gave up on every connection to {}",
+                self.config.server
+            ));
+        }
+        Ok(())
+    }
+
+    fn report(&self, stats: Stats) -> Result<()> {
+        self.stats_tx.send(stats).map_err(|e| anyhow!("{e}"))
+    }
+}
+
+/// Splits `buffer` into one encoded command buffer per connection.
+fn encode(buffer: &[Pixel], config: &Config, offset: (i32, i32)) -> Vec<Arc<CommandBuffer>> {
+    let threads = config.threads;
+    match config.partition {
+        Partition::Interleave => (0..threads)
+            .map(|conn_id| {
+                let pixels = buffer.iter().skip(conn_id).step_by(threads);
+                CommandBuffer::encode(
+                    pixels,
+                    config.protocol,
+                    offset,
+                    config.offset_command,
+                    (0, 0),
+                )
+            })
+            .map(Arc::new)
+            .collect(),
+        Partition::Tiles => tiles(buffer, threads)
+            .into_iter()
+            .map(|(origin, pixels)| {
+                CommandBuffer::encode(
+                    pixels.into_iter(),
+                    config.protocol,
+                    offset,
+                    config.offset_command,
+                    origin,
+                )
+            })
+            .map(Arc::new)
+            .collect(),
+    }
+}
+
+/// Cuts the box spanning `buffer` into a grid of `num_tiles` tiles that are as square as
+/// possible, returning the origin and pixels of every tile.
+fn tiles(buffer: &[Pixel], num_tiles: usize) -> Vec<((i32, i32), Vec<&Pixel>)> {
+    if buffer.is_empty() {
+        return (0..num_tiles).map(|_| ((0, 0), Vec::new())).collect();
+    }
+
+    // wrapped coordinates left of or above the image turn negative again
+    let coords = |px: &Pixel| (px.x as i32, px.y as i32);
+    let (min, max) = buffer.iter().map(coords).fold(
+        ((i32::MAX, i32::MAX), (i32::MIN, i32::MIN)),
+        |(min, max), (x, y)| ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y))),
+    );
+    let width = (max.0 - min.0 + 1) as usize;
+    let height = (max.1 - min.1 + 1) as usize;
+
+    let columns = (1..=num_tiles)
+        .filter(|columns| num_tiles.is_multiple_of(*columns))
+        .min_by(|a, b| {
+            let squareness = |columns: usize| {
+                let rows = num_tiles / columns;
+                let ratio = (width as f64 / columns as f64) / (height as f64 / rows as f64);
+                ratio.ln().abs()
+            };
+            squareness(*a).total_cmp(&squareness(*b))
+        })
+        .unwrap_or(1);
+    let rows = num_tiles / columns;
+
+    let mut tiles: Vec<_> = (0..num_tiles)
+        .map(|i| {
+            let origin = (
+                min.0 + ((i % columns) * width / columns) as i32,
+                min.1 + ((i / columns) * height / rows) as i32,
+            );
+            (origin, Vec::new())
+        })
+        .collect();
+
+    for px in buffer {
+        let (x, y) = coords(px);
+        let column = (x - min.0) as usize * columns / width;
+        let row = (y - min.1) as usize * rows / height;
+        tiles[row * columns + column].1.push(px);
+    }
+
+    tiles
+}
+
+async fn draw(connections: &[ConnectionTx], commands: &[Arc<CommandBuffer>]) -> Result<Stats> {
+    let mut set = JoinSet::new();
+    for (conn_id, (conn, commands)) in connections.iter().zip(commands.iter()).enumerate() {
+        let (tx, rx) = oneshot::channel();
+        conn.send(ConnectionJob::Draw {
+            commands: commands.clone(),
+            done: tx,
+        })?;
+        set.spawn(async move { (conn_id, rx.await) });
+    }
+
+    let mut stats = Stats::new(connections.len());
+    while let Some(res) = set.join_next().await {
+        let (conn_id, report) = res?;
+        stats.add(conn_id, report?);
+    }
+
+    Ok(stats)
+}
+
+/// Reads the current canvas colors at `coords`, spread across all connections.
+async fn read(
+    connections: &[ConnectionTx],
+    coords: &[(u32, u32)],
+    config: &Config,
+) -> Result<(Vec<Pixel>, Stats)> {
     let mut set = JoinSet::new();
-    for conn in connections.iter() {
+    for (conn_id, conn) in connections.iter().enumerate() {
+        let queries =
+            CommandBuffer::encode_reads(coords, conn_id, config.threads, config.offset_command);
         let (tx, rx) = oneshot::channel();
-        conn.send((buffer.clone(), tx))?;
-        set.spawn(rx);
+        conn.send(ConnectionJob::Read {
+            queries: Arc::new(queries),
+            done: tx,
+        })?;
+        set.spawn(async move { (conn_id, rx.await) });
     }
 
-    let mut stats = Stats::default();
+    let mut pixels = Vec::with_capacity(coords.len());
+    let mut stats = Stats::new(connections.len());
     while let Some(res) = set.join_next().await {
-        stats.errors += res??;
+        let (conn_id, res) = res?;
+        let (read, report) = res?;
+        pixels.extend(read);
+        stats.add(conn_id, report);
+    }
+
+    Ok((pixels, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Pixel as _, RgbaImage};
+
+    use super::*;
+    use crate::{
+        frames::Frame,
+        limit::Limits,
+        server::{Server, BACKGROUND},
+        Area,
+    };
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn limiter() -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(Limits::default()))
+    }
+
+    /// A 6x4 frame with a transparent hole. Translucent pixels are left out, they blend
+    /// again on every redraw.
+    fn frame() -> Frame {
+        let mut data = Vec::new();
+        for y in 0..4u8 {
+            for x in 0..6u8 {
+                let alpha = match (x, y) {
+                    (1, 1) | (2, 1) => 0,
+                    _ => 0xff,
+                };
+                data.extend_from_slice(&[x * 40, y * 60, 0x7f, alpha]);
+            }
+        }
+        Frame::decode(0.0, 6, &data).unwrap().0
+    }
+
+    /// `base` with `pixels` blended on at `offset`.
+    fn paint(base: &RgbaImage, pixels: &[Pixel], offset: (u32, u32)) -> RgbaImage {
+        let mut image = base.clone();
+        for px in pixels {
+            image
+                .get_pixel_mut(px.x + offset.0, px.y + offset.1)
+                .blend(&px.value);
+        }
+        image
+    }
+
+    #[tokio::test]
+    async fn draws_frame() {
+        let server = Server::local(32, 24, false).await.unwrap();
+        let (stats_tx, _stats_rx) = mpsc::unbounded_channel();
+        let bundle = ConnectionBundle::new(server.config(), stats_tx, None, limiter())
+            .await
+            .unwrap();
+
+        let frame = frame();
+        bundle
+            .update_buffer(frame.pixels.clone(), None, None, (0, 0))
+            .unwrap();
+
+        let expected = paint(&server.canvas(), &frame.pixels, (5, 7));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+    }
+
+    #[tokio::test]
+    async fn draws_frame_with_binary_protocol_and_offset_command() {
+        let server = Server::local(32, 24, true).await.unwrap();
+        let (stats_tx, _stats_rx) = mpsc::unbounded_channel();
+        let bundle = ConnectionBundle::new(
+            Config {
+                protocol: Protocol::Binary,
+                offset_command: true,
+                ..server.config()
+            },
+            stats_tx,
+            None,
+            limiter(),
+        )
+        .await
+        .unwrap();
+
+        let frame = frame();
+        bundle
+            .update_buffer(frame.pixels.clone(), None, None, (2, 1))
+            .unwrap();
+
+        let expected = paint(&server.canvas(), &frame.pixels, (7, 8));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+    }
+
+    #[tokio::test]
+    async fn draws_frame_in_tiles() {
+        let server = Server::local(32, 24, false).await.unwrap();
+        let (stats_tx, _stats_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            partition: Partition::Tiles,
+            offset_command: true,
+            ..server.config()
+        };
+        let bundle = ConnectionBundle::new(config, stats_tx, None, limiter())
+            .await
+            .unwrap();
+
+        let frame = frame();
+        bundle
+            .update_buffer(frame.pixels.clone(), None, None, (2, 1))
+            .unwrap();
+
+        let expected = paint(&server.canvas(), &frame.pixels, (7, 8));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            "wall.c3pixelflut.de".parse(),
+            Ok(Target {
+                address: "wall.c3pixelflut.de".to_string(),
+                port: None,
+                threads: None,
+                offset_x: None,
+                offset_y: None,
+            })
+        );
+        assert_eq!(
+            "10.0.0.2:1234,threads=4,x=100,y=20".parse(),
+            Ok(Target {
+                address: "10.0.0.2".to_string(),
+                port: Some(1234),
+                threads: Some(4),
+                offset_x: Some(100),
+                offset_y: Some(20),
+            })
+        );
+        assert!("10.0.0.2,speed=9000".parse::<Target>().is_err());
+        assert!(":1234".parse::<Target>().is_err());
+    }
+
+    #[tokio::test]
+    async fn restores_uncovered_pixels() {
+        let server = Server::local(32, 24, false).await.unwrap();
+        let (stats_tx, _stats_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            restore: true,
+            ..server.config()
+        };
+        let bundle = ConnectionBundle::new(config, stats_tx, None, limiter())
+            .await
+            .unwrap();
+
+        let frame = frame();
+        let background = RgbaImage::from_pixel(32, 24, BACKGROUND);
+
+        bundle
+            .update_buffer(
+                frame.pixels.clone(),
+                Some(frame.pixels.clone()),
+                None,
+                (0, 0),
+            )
+            .unwrap();
+        let expected = paint(&background, &frame.pixels, (5, 7));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+
+        bundle
+            .update_buffer(
+                frame.pixels.clone(),
+                Some(frame.pixels.clone()),
+                None,
+                (3, 1),
+            )
+            .unwrap();
+        let expected = paint(&background, &frame.pixels, (8, 8));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+    }
+
+    #[tokio::test]
+    async fn restores_without_reading_off_the_canvas() {
+        let server = Server::local(8, 8, false).await.unwrap();
+        let (stats_tx, mut stats_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            restore: true,
+            image_area: Area {
+                origin_x: 5,
+                origin_y: 0,
+                size_x: 6,
+                size_y: 4,
+            },
+            ..server.config()
+        };
+        let bundle = ConnectionBundle::new(config, stats_tx, None, limiter())
+            .await
+            .unwrap();
+
+        let frame = frame();
+        bundle
+            .update_buffer(frame.pixels.clone(), Some(frame.pixels), None, (0, 0))
+            .unwrap();
+
+        // unanswered reads would only fail after the read timeout
+        let stats = timeout(Duration::from_secs(1), stats_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.errors, 0);
+    }
+
+    #[tokio::test]
+    async fn stops_once_every_connection_gave_up() {
+        // nothing listens on a port that was just released
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let (stats_tx, mut stats_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            threads: 2,
+            reconnect: ReconnectPolicy {
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+                max_retries: Some(1),
+            },
+            ..Config::local(server, (32, 24))
+        };
+        let bundle = ConnectionBundle::new(config, stats_tx, None, limiter())
+            .await
+            .unwrap();
+        bundle
+            .update_buffer(frame().pixels, None, None, (0, 0))
+            .unwrap();
+
+        let mut errors = 0;
+        let mut passes = 0;
+        while let Some(stats) = timeout(WAIT, stats_rx.recv()).await.unwrap() {
+            errors += stats.errors;
+            passes += stats.frames;
+        }
+        assert_eq!(errors, 2);
+        assert_eq!(passes, 1);
+
+        let err = bundle.update_buffer(Vec::new(), None, None, (0, 0));
+        assert!(err.unwrap_err().to_string().contains("Gave up"));
+    }
+
+    #[tokio::test]
+    async fn restores_around_stale_snapshots() {
+        let server = Server::local(32, 24, false).await.unwrap();
+        let (stats_tx, _stats_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            restore: true,
+            ..server.config()
+        };
+        let region = Area {
+            origin_x: 0,
+            origin_y: 0,
+            size_x: 32,
+            size_y: 24,
+        };
+        let mut shadow = Shadow::spawn(&config, region, Duration::from_secs(1), limiter());
+        let bundle = ConnectionBundle::new(config, stats_tx, Some(shadow.clone()), limiter())
+            .await
+            .unwrap();
+
+        let frame = frame();
+        let background = RgbaImage::from_pixel(32, 24, BACKGROUND);
+        let here = paint(&background, &frame.pixels, (5, 7));
+        let there = paint(&background, &frame.pixels, (15, 7));
+        let go = async |offset, expected: &RgbaImage| {
+            bundle
+                .update_buffer(
+                    frame.pixels.clone(),
+                    Some(frame.pixels.clone()),
+                    None,
+                    offset,
+                )
+                .unwrap();
+            assert!(server.wait_for(expected, WAIT).await == *expected);
+        };
+
+        go((0, 0), &here).await;
+        // wait for a snapshot showing the image
+        let generation = shadow.generation();
+        while shadow.generation() < generation + 2 {
+            shadow.changed().await;
+        }
+
+        // back and forth within one snapshot interval
+        go((10, 0), &there).await;
+        go((0, 0), &here).await;
+        go((10, 0), &there).await;
+    }
+
+    #[tokio::test]
+    async fn defends_only_the_pixels_painted_over() {
+        let server = Server::local(32, 24, false).await.unwrap();
+        let (stats_tx, mut stats_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            defend: true,
+            ..server.config()
+        };
+        let region = config.image_area.clone();
+        let shadow = Shadow::spawn(&config, region, Duration::from_millis(20), limiter());
+        let bundle = ConnectionBundle::new(config, stats_tx, Some(shadow), limiter())
+            .await
+            .unwrap();
+
+        let frame = frame();
+        bundle
+            .update_buffer(frame.pixels.clone(), None, None, (0, 0))
+            .unwrap();
+        let expected = paint(&server.canvas(), &frame.pixels, (5, 7));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+
+        // a pass without any pixels means a snapshot showed the whole frame in place
+        let settled = async {
+            while let Some(stats) = stats_rx.recv().await {
+                if stats.pixels == 0 {
+                    return;
+                }
+            }
+        };
+        timeout(WAIT, settled).await.unwrap();
+
+        let mut vandal = tokio::net::TcpStream::connect(server.addr()).await.unwrap();
+        vandal.write_all(b"PX 5 7 ffffff\n").await.unwrap();
+
+        // the next passes only send the pixel that was painted over
+        loop {
+            let stats = timeout(WAIT, stats_rx.recv()).await.unwrap().unwrap();
+            assert!(stats.pixels <= 1, "re-sent {} pixels", stats.pixels);
+            if stats.pixels == 1 {
+                break;
+            }
+        }
+        assert!(server.wait_for(&expected, WAIT).await == expected);
+    }
+
+    #[tokio::test]
+    async fn keeps_moved_images_on_smaller_canvases() {
+        let server = Server::local(16, 12, false).await.unwrap();
+        let (stats_tx, _stats_rx) = mpsc::unbounded_channel();
+        let bundle = ConnectionBundle::new(server.config(), stats_tx, None, limiter())
+            .await
+            .unwrap();
+
+        // moved right up to the edge of a larger primary canvas
+        let frame = frame();
+        bundle
+            .update_buffer(frame.pixels.clone(), None, None, (21, 13))
+            .unwrap();
+
+        let expected = paint(&server.canvas(), &frame.pixels, (10, 8));
+        assert!(server.wait_for(&expected, WAIT).await == expected);
     }
-    stats_tx.send(stats).map_err(|e| anyhow!("{e}"))
 }
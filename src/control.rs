@@ -0,0 +1,51 @@
+use serde::Serialize;
+
+use crate::{frames::FrameSource, settings::FilterSpec};
+
+/// Changes applied to the running render loop, without touching the connections.
+pub enum Control {
+    /// Replaces the whole filter pipeline, with every filter enabled
+    Filters(Vec<FilterSpec>),
+    /// Changes the parameters of the `index`th filter
+    Filter {
+        index: usize,
+        spec: FilterSpec,
+    },
+    /// Turns the `index`th filter on or off, keeping its place in the pipeline
+    EnableFilter {
+        index: usize,
+        enabled: bool,
+    },
+    /// Moves the image on the `server`th server, or on all of them
+    Origin {
+        server: Option<usize>,
+        x: u32,
+        y: u32,
+    },
+    /// Switches to a new, already opened source
+    Source {
+        name: String,
+        source: FrameSource,
+        size: (u32, u32),
+    },
+    /// Stops sending pixels to every server, the connections stay open
+    Pause,
+    Resume,
+}
+
+/// What the render loop is currently doing, updated after every change.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub file: String,
+    /// Image origin on every server
+    pub origins: Vec<(u32, u32)>,
+    pub filters: Vec<FilterStatus>,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterStatus {
+    #[serde(flatten)]
+    pub spec: FilterSpec,
+    pub enabled: bool,
+}
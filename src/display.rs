@@ -0,0 +1,514 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::IsTerminal,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    io::{stdout, AsyncWriteExt},
+    sync::{broadcast, mpsc},
+    time::{interval, Instant},
+};
+
+use crate::{
+    conn::{ConnectionState, Stats},
+    metrics::Metrics,
+    Pixel,
+};
+
+/// How often the status line is refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the dashboard is redrawn.
+const DASHBOARD_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of samples kept for the sparklines.
+const HISTORY_LEN: usize = 60;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Stats updates a subscriber may fall behind before it starts missing some.
+const STATS_BROADCAST_CAPACITY: usize = 1024;
+
+/// Static information shown on the dashboard.
+#[derive(Debug, Clone)]
+pub struct DisplayInfo {
+    pub servers: Vec<ServerInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub address: String,
+    pub canvas_size: (u32, u32),
+    pub threads: usize,
+}
+
+/// What the render loop is currently doing.
+#[derive(Default, Clone)]
+struct FrameInfo {
+    source: String,
+    source_size: (u32, u32),
+    /// Descriptions of the active filters
+    filters: Vec<String>,
+    index: usize,
+    total: Option<usize>,
+    /// Copy of the latest buffer, only taken when the dashboard asks for one
+    preview: Option<(Vec<Pixel>, (i32, i32))>,
+    preview_due: bool,
+    /// Latest problem worth telling the user about, e.g. a config that failed to reload
+    message: Option<String>,
+}
+
+impl FrameInfo {
+    /// Takes what the dashboard shows, including the pending preview, and asks for the next
+    /// preview. Rendering works on the copy so the render loop isn't held up.
+    fn take(&mut self) -> FrameInfo {
+        let preview = self.preview.take();
+        self.preview_due = true;
+        FrameInfo {
+            preview,
+            ..self.clone()
+        }
+    }
+}
+
+/// Handle for feeding the display from the render loop and the connections.
+#[derive(Clone)]
+pub struct Display {
+    stats_tx: mpsc::UnboundedSender<(usize, Stats)>,
+    /// Every stats update together with its server, for listeners besides the display
+    stats_broadcast: broadcast::Sender<(usize, Arc<Stats>)>,
+    frame: Arc<Mutex<FrameInfo>>,
+    /// Whether the dashboard owns the terminal
+    dashboard: bool,
+}
+
+impl Display {
+    /// A channel for the stats of the bundle flooding the `server`th server.
+    pub fn stats_tx(&self, server: usize) -> mpsc::UnboundedSender<Stats> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Stats>();
+        let stats_tx = self.stats_tx.clone();
+        let stats_broadcast = self.stats_broadcast.clone();
+        tokio::spawn(async move {
+            while let Some(stats) = rx.recv().await {
+                if stats_broadcast.receiver_count() > 0 {
+                    let _ = stats_broadcast.send((server, Arc::new(stats.clone())));
+                }
+                if stats_tx.send((server, stats)).is_err() {
+                    return;
+                }
+            }
+        });
+        tx
+    }
+
+    /// Sends the stats of every server as they come in to whoever subscribes to it. Slow
+    /// receivers miss updates.
+    pub fn stats_broadcast(&self) -> broadcast::Sender<(usize, Arc<Stats>)> {
+        self.stats_broadcast.clone()
+    }
+
+    pub fn set_source(&self, name: &str, size: (u32, u32)) {
+        let mut frame = self.frame.lock().unwrap();
+        frame.source = name.to_string();
+        frame.source_size = size;
+    }
+
+    pub fn set_filters(&self, filters: Vec<String>) {
+        self.frame.lock().unwrap().filters = filters;
+    }
+
+    /// Shows `message` on the dashboard until the next one comes in. Without a dashboard
+    /// it is printed right away.
+    pub fn set_message(&self, message: String) {
+        if self.dashboard {
+            self.frame.lock().unwrap().message = Some(message);
+        } else {
+            println!("{message}");
+        }
+    }
+
+    /// Records the frame that is about to be sent. The buffer is only copied when the
+    /// dashboard is due for a new preview, so this is cheap to call on every frame.
+    pub fn frame(&self, index: usize, total: Option<usize>, buffer: &[Pixel], offset: (i32, i32)) {
+        let mut frame = self.frame.lock().unwrap();
+        frame.index = index;
+        frame.total = total;
+        if frame.preview_due {
+            frame.preview = Some((buffer.to_vec(), offset));
+            frame.preview_due = false;
+        }
+    }
+}
+
+/// Stats collected since the last refresh.
+struct Window {
+    start: Instant,
+    pixels: usize,
+    bytes: usize,
+    frames: usize,
+    /// Number of connections per server
+    threads: Vec<usize>,
+    /// Pixels and bytes sent per connection, the connections of all servers one after another
+    connection_pixels: Vec<usize>,
+    connection_bytes: Vec<usize>,
+    states: Vec<ConnectionState>,
+    write_latencies: Vec<Duration>,
+}
+
+impl Window {
+    fn new(threads: Vec<usize>) -> Self {
+        let total = threads.iter().sum();
+        Self {
+            start: Instant::now(),
+            pixels: 0,
+            bytes: 0,
+            frames: 0,
+            threads,
+            connection_pixels: vec![0; total],
+            connection_bytes: vec![0; total],
+            states: vec![ConnectionState::default(); total],
+            write_latencies: Vec::new(),
+        }
+    }
+
+    /// Starts the next window, keeping the connection states until they report again.
+    fn next(&mut self) -> Self {
+        Self {
+            states: std::mem::take(&mut self.states),
+            ..Self::new(self.threads.clone())
+        }
+    }
+
+    fn add(&mut self, server: usize, stats: Stats) {
+        self.pixels += stats.pixels;
+        self.bytes += stats.bytes;
+        // every server completes its own passes, count the first one only
+        if server == 0 {
+            self.frames += stats.frames;
+        }
+
+        let base: usize = self.threads.iter().take(server).sum();
+        let threads = self.threads.get(server).copied().unwrap_or_default();
+        for (conn_id, conn) in stats.connections.into_iter().enumerate() {
+            if conn_id >= threads {
+                continue;
+            }
+            let conn_id = base + conn_id;
+            self.connection_pixels[conn_id] += conn.pixels;
+            self.connection_bytes[conn_id] += conn.bytes;
+            self.states[conn_id] = conn.state;
+            self.write_latencies.extend(conn.write_latencies);
+        }
+    }
+
+    fn secs(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn connected(&self) -> usize {
+        self.states
+            .iter()
+            .filter(|state| **state == ConnectionState::Connected)
+            .count()
+    }
+
+    /// The write latency below which `p` percent of all writes finished.
+    fn latency_percentile(&mut self, p: usize) -> Duration {
+        if self.write_latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        self.write_latencies.sort_unstable();
+        let idx = (self.write_latencies.len() - 1) * p / 100;
+        self.write_latencies[idx]
+    }
+
+    fn status(&mut self, errors: usize, reconnects: usize) -> String {
+        let secs = self.secs();
+        let (slowest, slowest_pixels) = self
+            .connection_pixels
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|(_, pixels)| *pixels)
+            .unwrap_or_default();
+
+        format!(
+            "\rThreads: {threads} ({connected} up)  |  {pps} px/s  |  {bps}B/s  |  {fps:.1} fps  |  Write p50/p90/p99: {p50:.1?}/{p90:.1?}/{p99:.1?}  |  Slowest: #{slowest} {slowest_pps} px/s  |  Errors: {errors}  |  Reconnects: {reconnects}   ",
+            threads = self.states.len(),
+            connected = self.connected(),
+            pps = si(self.pixels as f64 / secs),
+            bps = si(self.bytes as f64 / secs),
+            fps = self.frames as f64 / secs,
+            p50 = self.latency_percentile(50),
+            p90 = self.latency_percentile(90),
+            p99 = self.latency_percentile(99),
+            slowest_pps = si(slowest_pixels as f64 / secs),
+        )
+    }
+}
+
+/// Full-screen view of everything going on, redrawn in place.
+struct Dashboard {
+    info: DisplayInfo,
+    pps_history: VecDeque<f64>,
+    bps_history: VecDeque<f64>,
+    preview: Vec<String>,
+}
+
+impl Dashboard {
+    fn new(info: DisplayInfo) -> Self {
+        Self {
+            info,
+            pps_history: VecDeque::with_capacity(HISTORY_LEN),
+            bps_history: VecDeque::with_capacity(HISTORY_LEN),
+            preview: Vec::new(),
+        }
+    }
+
+    fn render(
+        &mut self,
+        window: &mut Window,
+        frame: FrameInfo,
+        errors: usize,
+        reconnects: usize,
+    ) -> String {
+        let (columns, rows) = crossterm::terminal::size().unwrap_or((100, 40));
+        let secs = window.secs();
+        let pps = window.pixels as f64 / secs;
+        let bps = window.bytes as f64 / secs;
+
+        for (history, value) in [(&mut self.pps_history, pps), (&mut self.bps_history, bps)] {
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(value);
+        }
+
+        let mut lines = Vec::new();
+        lines.push(format!(
+            "c3pixelflut  |  {} ({}x{})",
+            frame.source, frame.source_size.0, frame.source_size.1,
+        ));
+        lines.push(format!(
+            "Frame {}/{}  |  {:.1} fps  |  Errors: {errors}  |  Reconnects: {reconnects}",
+            frame.index + 1,
+            frame
+                .total
+                .map_or("?".to_string(), |total| total.to_string()),
+            window.frames as f64 / secs,
+        ));
+        if let Some(message) = &frame.message {
+            lines.push(message.clone());
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{:>8} px/s  {}",
+            si(pps),
+            sparkline(&self.pps_history)
+        ));
+        lines.push(format!(
+            "{:>8}B/s   {}",
+            si(bps),
+            sparkline(&self.bps_history)
+        ));
+        lines.push(format!(
+            "Write latency p50/p90/p99: {:.1?} / {:.1?} / {:.1?}",
+            window.latency_percentile(50),
+            window.latency_percentile(90),
+            window.latency_percentile(99),
+        ));
+        lines.push(String::new());
+
+        lines.push(format!("Filters ({})", frame.filters.len()));
+        for filter in frame.filters.iter() {
+            lines.push(format!("  {filter}"));
+        }
+        lines.push(String::new());
+
+        lines.push(format!(
+            "Connections ({}/{} up)",
+            window.connected(),
+            window.states.len()
+        ));
+        let mut conn_ids = 0..window.states.len();
+        for server in self.info.servers.iter() {
+            lines.push(format!(
+                "  {}  |  canvas {}x{}",
+                server.address, server.canvas_size.0, server.canvas_size.1
+            ));
+            for (conn_id, i) in conn_ids.by_ref().take(server.threads).enumerate() {
+                lines.push(format!(
+                    "    #{conn_id:<3} {:<10} {:>8} px/s {:>8}B/s",
+                    format!("{:?}", window.states[i]),
+                    si(window.connection_pixels[i] as f64 / secs),
+                    si(window.connection_bytes[i] as f64 / secs),
+                ));
+            }
+        }
+        lines.push(String::new());
+
+        // the preview gets whatever space is left
+        if let Some((buffer, offset)) = frame.preview {
+            let height = (rows as usize).saturating_sub(lines.len() + 1);
+            self.preview = preview(&buffer, offset, frame.source_size, columns as usize, height);
+        }
+
+        let mut out = String::from("\x1b[H");
+        for line in lines.iter() {
+            let line: String = line.chars().take(columns as usize).collect();
+            let _ = write!(out, "{line}\x1b[K\r\n");
+        }
+        for line in self.preview.iter() {
+            let _ = write!(out, "{line}\x1b[0m\x1b[K\r\n");
+        }
+        out.push_str("\x1b[J");
+        out
+    }
+}
+
+/// Renders `values` as a bar chart scaled to their maximum.
+fn sparkline(values: &VecDeque<f64>) -> String {
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|value| {
+            if max <= 0.0 {
+                SPARKS[0]
+            } else {
+                SPARKS[((value / max) * (SPARKS.len() - 1) as f64).round() as usize]
+            }
+        })
+        .collect()
+}
+
+/// Scales the image down to fit `columns` x `rows` terminal cells, using half blocks
+/// with true color so every cell shows two pixels.
+fn preview(
+    buffer: &[Pixel],
+    offset: (i32, i32),
+    size: (u32, u32),
+    columns: usize,
+    rows: usize,
+) -> Vec<String> {
+    if size.0 == 0 || size.1 == 0 || columns == 0 || rows == 0 {
+        return Vec::new();
+    }
+
+    let scale = (size.0 as f64 / columns as f64)
+        .max(size.1 as f64 / (rows * 2) as f64)
+        .max(1.0);
+    let width = (size.0 as f64 / scale) as usize;
+    let height = (size.1 as f64 / scale) as usize;
+
+    let mut cells: Vec<Option<[u8; 3]>> = vec![None; width * height];
+    for px in buffer.iter() {
+        // wrapped coordinates turn negative here and end up outside the preview
+        let x = ((px.x as i32 + offset.0) as f64 / scale) as isize;
+        let y = ((px.y as i32 + offset.1) as f64 / scale) as isize;
+        if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+            cells[y as usize * width + x as usize] = Some([px.value[0], px.value[1], px.value[2]]);
+        }
+    }
+
+    (0..height)
+        .step_by(2)
+        .map(|y| {
+            let mut line = String::new();
+            for x in 0..width {
+                let top = cells[y * width + x];
+                let bottom = cells.get((y + 1) * width + x).copied().flatten();
+                match (top, bottom) {
+                    (None, None) => line.push_str("\x1b[0m "),
+                    (Some([r, g, b]), None) => {
+                        let _ = write!(line, "\x1b[0m\x1b[38;2;{r};{g};{b}m▀");
+                    }
+                    (None, Some([r, g, b])) => {
+                        let _ = write!(line, "\x1b[0m\x1b[38;2;{r};{g};{b}m▄");
+                    }
+                    (Some([r, g, b]), Some([br, bg, bb])) => {
+                        let _ = write!(line, "\x1b[38;2;{r};{g};{b};48;2;{br};{bg};{bb}m▀");
+                    }
+                }
+            }
+            line
+        })
+        .collect()
+}
+
+/// Formats `value` with an SI prefix, e.g. `12.3k`.
+fn si(value: f64) -> String {
+    match value {
+        v if v >= 1e9 => format!("{:.1}G", v / 1e9),
+        v if v >= 1e6 => format!("{:.1}M", v / 1e6),
+        v if v >= 1e3 => format!("{:.1}k", v / 1e3),
+        v => format!("{v:.0}"),
+    }
+}
+
+/// Starts the display: a full-screen dashboard when running in a terminal, a single
+/// status line otherwise. All stats are recorded into `metrics` as well.
+pub async fn start_display(info: DisplayInfo, metrics: Arc<Metrics>) -> Result<Display> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let frame = Arc::new(Mutex::new(FrameInfo::default()));
+
+    let threads = info.servers.iter().map(|server| server.threads).collect();
+    let is_terminal = std::io::stdout().is_terminal();
+    let mut dashboard = is_terminal.then(|| Dashboard::new(info));
+    let shared_frame = frame.clone();
+
+    tokio::spawn(async move {
+        let mut errors = 0;
+        let mut reconnects = 0;
+        let mut window = Window::new(threads);
+        let mut refresh = interval(if dashboard.is_some() {
+            DASHBOARD_INTERVAL
+        } else {
+            REFRESH_INTERVAL
+        });
+
+        if dashboard.is_some() {
+            // start from a clean screen, later redraws only overwrite it
+            let _ = stdout().write_all(b"\x1b[2J").await;
+        }
+
+        loop {
+            tokio::select! {
+                stats = rx.recv() => {
+                    let Some((server, stats)) = stats else {
+                        return;
+                    };
+                    metrics.record(server, &stats);
+                    errors += stats.errors;
+                    reconnects += stats.reconnects;
+                    window.add(server, stats);
+                    metrics.set_connections_up(window.connected());
+                }
+                _ = refresh.tick() => {
+                    let out = match &mut dashboard {
+                        Some(dashboard) => {
+                            let frame = shared_frame.lock().unwrap().take();
+                            dashboard.render(&mut window, frame, errors, reconnects)
+                        }
+                        None => window.status(errors, reconnects),
+                    };
+                    stdout().write_all(out.as_bytes()).await.unwrap();
+                    stdout().flush().await.unwrap();
+
+                    window = window.next();
+                }
+            }
+        }
+    });
+
+    Ok(Display {
+        stats_tx: tx,
+        stats_broadcast: broadcast::channel(STATS_BROADCAST_CAPACITY).0,
+        frame,
+        dashboard: is_terminal,
+    })
+}
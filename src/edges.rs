@@ -20,6 +20,14 @@ impl Edges {
         Self(val)
     }
 
+    pub(super) fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
     pub fn has_edge(&self, edge: Edge) -> bool {
         self.0 & edge as u8 > 0
     }
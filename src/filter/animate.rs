@@ -0,0 +1,234 @@
+use std::{collections::BTreeMap, f64::consts::TAU, time::Duration};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{Filter, FrameContext};
+
+/// Shape of a [`Curve::Lfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Wave {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+/// How a [`Curve::Keyframes`] moves from one keyframe to the next.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    /// Holds the value until the next keyframe
+    Step,
+    /// Starts slow
+    In,
+    /// Ends slow
+    Out,
+    /// Starts and ends slow
+    InOut,
+}
+
+impl Easing {
+    /// Maps the progress `u` between two keyframes, both in `0..=1`.
+    fn apply(&self, u: f64) -> f64 {
+        match self {
+            Easing::Linear => u,
+            Easing::Step => 0.0,
+            Easing::In => u * u,
+            Easing::Out => 1.0 - (1.0 - u) * (1.0 - u),
+            Easing::InOut => u * u * (3.0 - 2.0 * u),
+        }
+    }
+}
+
+/// A parameter value changing over time, in seconds since the filter got its first frame.
+///
+/// ```toml
+/// [filter.animate.alpha]
+/// lfo = "sine"
+/// period = 2.0
+/// min = 64
+/// max = 255
+///
+/// [filter.animate.speed]
+/// keyframes = [[0, 1], [2.5, 20], [5, 1]]
+/// ease = "in-out"
+/// loop = true
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Curve {
+    /// Oscillates between `min` and `max`, starting at `min`
+    Lfo {
+        lfo: Wave,
+        /// Seconds per cycle
+        period: f64,
+        min: f64,
+        max: f64,
+        /// Shift of the cycle, as a fraction of the period
+        #[serde(default)]
+        phase: f64,
+    },
+    /// Goes through `(time, value)` pairs, holding the first and last value outside of them
+    Keyframes {
+        keyframes: Vec<(f64, f64)>,
+        #[serde(default)]
+        ease: Easing,
+        /// Starts over after the last keyframe
+        #[serde(default, rename = "loop")]
+        looped: bool,
+    },
+}
+
+impl Curve {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Curve::Lfo { period, .. } if *period <= 0.0 => {
+                Err(anyhow!("LFO period must be positive"))
+            }
+            Curve::Keyframes { keyframes, .. } if keyframes.is_empty() => {
+                Err(anyhow!("Keyframes must not be empty"))
+            }
+            Curve::Keyframes { keyframes, .. }
+                if keyframes.windows(2).any(|pair| pair[0].0 >= pair[1].0) =>
+            {
+                Err(anyhow!("Keyframe times must be increasing"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The value `t` seconds in.
+    pub fn value(&self, t: f64) -> f64 {
+        match self {
+            Curve::Lfo {
+                lfo,
+                period,
+                min,
+                max,
+                phase,
+            } => {
+                let p = (t / period + phase).rem_euclid(1.0);
+                let w = match lfo {
+                    Wave::Sine => 0.5 - 0.5 * (TAU * p).cos(),
+                    Wave::Saw => p,
+                    Wave::Square => {
+                        if p < 0.5 {
+                            0.0
+                        } else {
+                            1.0
+                        }
+                    }
+                    Wave::Triangle => 1.0 - (2.0 * p - 1.0).abs(),
+                };
+                min + (max - min) * w
+            }
+            Curve::Keyframes {
+                keyframes,
+                ease,
+                looped,
+            } => {
+                let (first, last) = (keyframes[0], keyframes[keyframes.len() - 1]);
+                let t = if *looped && last.0 > 0.0 {
+                    t.rem_euclid(last.0)
+                } else {
+                    t
+                };
+
+                match keyframes.iter().position(|(time, _)| *time > t) {
+                    Some(0) => first.1,
+                    None => last.1,
+                    Some(i) => {
+                        let ((t0, v0), (t1, v1)) = (keyframes[i - 1], keyframes[i]);
+                        v0 + (v1 - v0) * ease.apply((t - t0) / (t1 - t0))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Drives parameters of the wrapped filter by [`Curve`]s, updated before every frame.
+#[derive(Debug)]
+pub struct Animated {
+    filter: Box<dyn Filter>,
+    params: BTreeMap<String, Curve>,
+    /// Elapsed time of the first frame, curves start from there
+    start: Option<Duration>,
+}
+
+impl Animated {
+    pub fn new(filter: Box<dyn Filter>, params: BTreeMap<String, Curve>) -> Self {
+        Self {
+            filter,
+            params,
+            start: None,
+        }
+    }
+}
+
+impl Filter for Animated {
+    fn transform_buffer(
+        &mut self,
+        context: &FrameContext,
+        buffer: &mut Vec<crate::Pixel>,
+        restore: &mut Option<Vec<crate::Pixel>>,
+        offset: &mut (i32, i32),
+    ) {
+        let start = *self.start.get_or_insert(context.elapsed);
+        let t = context.elapsed.saturating_sub(start).as_secs_f64();
+        for (name, curve) in &self.params {
+            self.filter.set_param(name, curve.value(t));
+        }
+        self.filter
+            .transform_buffer(context, buffer, restore, offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lfos_start_at_min_and_reach_max() {
+        for lfo in [Wave::Sine, Wave::Saw, Wave::Square, Wave::Triangle] {
+            let curve = Curve::Lfo {
+                lfo,
+                period: 2.0,
+                min: 10.0,
+                max: 20.0,
+                phase: 0.0,
+            };
+            assert_eq!(curve.value(0.0), 10.0, "{lfo:?}");
+
+            let max = (0..200)
+                .map(|i| curve.value(i as f64 / 100.0))
+                .fold(f64::MIN, f64::max);
+            assert!((max - 20.0).abs() < 0.2, "{lfo:?} peaks at {max}");
+        }
+    }
+
+    #[test]
+    fn keyframes_ease_between_values() {
+        let curve = Curve::Keyframes {
+            keyframes: vec![(1.0, 0.0), (3.0, 100.0)],
+            ease: Easing::InOut,
+            looped: false,
+        };
+        assert_eq!(curve.value(0.0), 0.0);
+        assert_eq!(curve.value(2.0), 50.0);
+        assert!(curve.value(1.5) < 25.0);
+        assert_eq!(curve.value(10.0), 100.0);
+
+        let looped = Curve::Keyframes {
+            keyframes: vec![(0.0, 0.0), (2.0, 100.0)],
+            ease: Easing::Step,
+            looped: true,
+        };
+        assert_eq!(looped.value(3.0), 0.0);
+        assert!(looped.validate().is_ok());
+    }
+}
@@ -1,7 +1,8 @@
 use image::{Pixel, Rgba};
 
-use super::Filter;
+use super::{Filter, FrameContext};
 
+#[derive(Debug)]
 pub struct Blend {
     color: Rgba<u8>,
 }
@@ -15,11 +16,24 @@ impl Blend {
 impl Filter for Blend {
     fn transform_buffer(
         &mut self,
+        _context: &FrameContext,
         buffer: &mut Vec<crate::Pixel>,
         _restore: &mut Option<Vec<crate::Pixel>>,
+        _offset: &mut (i32, i32),
     ) {
         for px in buffer {
             px.value.blend(&self.color);
         }
     }
+
+    fn set_param(&mut self, name: &str, value: f64) {
+        let channel = match name {
+            "red" => 0,
+            "green" => 1,
+            "blue" => 2,
+            "alpha" => 3,
+            _ => return,
+        };
+        self.color[channel] = value.clamp(0.0, 255.0) as u8;
+    }
 }
@@ -3,38 +3,38 @@ use std::ops::Range;
 use image::Rgba;
 use rand::random_range;
 
-use super::Filter;
+use super::{Filter, FrameContext};
 use crate::{
     edges::{Edge, Edges},
-    Area, Config, Pixel,
+    Pixel,
 };
 
 const VEC_RANGE: Range<i8> = 0..4;
 
+/// The direction vector is given in pixels per step.
+const STEPS_PER_SECOND: f32 = 30.0;
+
+#[derive(Debug)]
 pub struct Bounce {
-    base_x: i32,
-    base_y: i32,
+    base_x: f32,
+    base_y: f32,
 
     vec_x: i8,
     vec_y: i8,
 
-    screen_x: u32,
-    screen_y: u32,
-    area: Area,
-
     speed: i8,
 }
 
 impl Bounce {
-    pub fn new(config: &Config, speed: i8) -> Self {
+    /// Fastest speed in either direction, the random part of a direction is added on top.
+    pub const MAX_SPEED: i8 = i8::MAX - VEC_RANGE.end;
+
+    pub fn new(speed: i8) -> Self {
         Self {
-            base_x: 0,
-            base_y: 0,
+            base_x: 0.0,
+            base_y: 0.0,
             vec_x: random_range(VEC_RANGE) + speed,
             vec_y: random_range(VEC_RANGE) + speed,
-            screen_x: config.canvas_size.0,
-            screen_y: config.canvas_size.1,
-            area: config.image_area.clone(),
             speed,
         }
     }
@@ -43,43 +43,51 @@ impl Bounce {
 impl Filter for Bounce {
     fn transform_buffer(
         &mut self,
+        context: &FrameContext,
         buffer: &mut Vec<crate::Pixel>,
         restore: &mut Option<Vec<crate::Pixel>>,
+        offset: &mut (i32, i32),
     ) {
         let (mut change_x, mut change_y) = (false, false);
+        let area = &context.image_area;
+        let (screen_x, screen_y) = context.canvas_size;
 
-        self.base_x += self.vec_x as i32;
-        self.base_y += self.vec_y as i32;
+        let steps = context.delta.as_secs_f32() * STEPS_PER_SECOND;
+        self.base_x += self.vec_x as f32 * steps;
+        self.base_y += self.vec_y as f32 * steps;
 
-        if (self.base_x + self.area.origin_x as i32) < 0 {
-            self.base_x = -(self.area.origin_x as i32); // sums up to 0
+        if self.base_x + (area.origin_x as f32) < 0.0 {
+            self.base_x = -(area.origin_x as f32); // sums up to 0
             change_x = true;
         }
-        if (self.base_x + self.area.size_x as i32) >= self.screen_x as i32 {
-            self.base_x = (self.screen_x - self.area.size_x) as i32;
+        if self.base_x + area.size_x as f32 >= screen_x as f32 {
+            self.base_x = screen_x.saturating_sub(area.size_x) as f32;
             change_x = true;
         }
 
-        if (self.base_y + self.area.origin_y as i32) < 0 {
-            self.base_y = -(self.area.origin_y as i32); // sums up to 0
+        if self.base_y + (area.origin_y as f32) < 0.0 {
+            self.base_y = -(area.origin_y as f32); // sums up to 0
             change_y = true;
         }
-        if (self.base_y + self.area.size_y as i32) >= self.screen_y as i32 {
-            self.base_y = (self.screen_y - self.area.size_y) as i32;
+        if self.base_y + area.size_y as f32 >= screen_y as f32 {
+            self.base_y = screen_y.saturating_sub(area.size_y) as f32;
             change_y = true;
         }
 
-        for px in buffer {
-            px.x = (px.x as i32 + self.base_x) as u32;
-            px.y = (px.y as i32 + self.base_y) as u32;
+        offset.0 += self.base_x.round() as i32;
+        offset.1 += self.base_y.round() as i32;
 
-            if let Some(restore) = restore {
-                let size = VEC_RANGE.end + self.speed;
+        if let Some(restore) = restore {
+            // the farthest the image may have moved since the last frame
+            let size = ((VEC_RANGE.end + self.speed) as f32 * steps.max(1.0)).ceil() as i32;
 
+            // coordinates left of / above the image wrap around, they are resolved
+            // against the offset when the buffer gets encoded
+            for px in buffer.iter() {
                 if (self.vec_x < 0 || change_x) && px.edges.has_edge(Edge::Right) {
                     for i in 0..size {
                         restore.push(Pixel {
-                            x: px.x - i as u32,
+                            x: px.x.wrapping_sub(i as u32),
                             y: px.y,
                             value: Rgba::from([0, 0, 0, 255]),
                             edges: Edges::default(),
@@ -99,7 +107,7 @@ impl Filter for Bounce {
                     for i in 0..size {
                         restore.push(Pixel {
                             x: px.x,
-                            y: px.y - i as u32,
+                            y: px.y.wrapping_sub(i as u32),
                             value: Rgba::from([0, 0, 0, 255]),
                             edges: Edges::default(),
                         });
@@ -128,6 +136,29 @@ impl Filter for Bounce {
             self.vec_x = change_direction(self.vec_x, self.speed, false);
         }
     }
+
+    fn set_param(&mut self, name: &str, value: f64) {
+        if name == "speed" {
+            let max = Self::MAX_SPEED as f64;
+            let speed = value.clamp(-max, max) as i8;
+            // keep moving the same way, just at the new speed
+            self.vec_x = rescale(self.vec_x, self.speed, speed);
+            self.vec_y = rescale(self.vec_y, self.speed, speed);
+            self.speed = speed;
+        }
+    }
+}
+
+/// `direction` with its speed part swapped from `old` to `new`, keeping the random part
+/// and the sign.
+fn rescale(direction: i8, old: i8, new: i8) -> i8 {
+    let random = (direction.abs() as i16 - old as i16)
+        .clamp(VEC_RANGE.start as i16, VEC_RANGE.end as i16 - 1) as i8;
+    if direction < 0 {
+        -(random + new)
+    } else {
+        random + new
+    }
 }
 
 fn change_direction(direction: i8, speed: i8, invert: bool) -> i8 {
@@ -143,3 +174,25 @@ fn change_direction(direction: i8, speed: i8, invert: bool) -> i8 {
         x
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_changes_apply_right_away() {
+        let mut bounce = Bounce::new(10);
+        bounce.vec_x = -12;
+        bounce.vec_y = 11;
+
+        bounce.set_param("speed", 2.0);
+        assert_eq!((bounce.vec_x, bounce.vec_y), (-4, 3));
+
+        bounce.set_param("speed", 20.0);
+        assert_eq!((bounce.vec_x, bounce.vec_y), (-22, 21));
+
+        bounce.set_param("speed", -200.0);
+        bounce.set_param("speed", 200.0);
+        assert_eq!(bounce.speed, Bounce::MAX_SPEED);
+    }
+}
@@ -1,29 +1,31 @@
+use std::time::Duration;
+
 use image::Rgba;
 use rand::{random, rngs::StdRng, Rng, SeedableRng};
 
 use crate::{
     edges::{Edge, Edges},
-    Config, Pixel, RESTORE_DEBUG_COLOR,
+    Pixel, RESTORE_DEBUG_COLOR,
 };
 
-use super::Filter;
+use super::{Filter, FrameContext};
 
 const PRESET: [i32; 10] = [-3, -2, -1, 0, 0, 0, 0, 1, 2, 3];
 
+/// How long the lines keep their shifts.
+const GLITCH_INTERVAL: Duration = Duration::from_millis(125);
+
+#[derive(Debug)]
 pub struct Glitch {
     factor: i32,
-    screen_x: u32,
     seed: u64,
-    index: u64,
 }
 
 impl Glitch {
-    pub fn new(config: &Config, factor: i32) -> Self {
+    pub fn new(factor: i32) -> Self {
         Self {
             factor,
-            screen_x: config.canvas_size.0,
             seed: random(),
-            index: 0,
         }
     }
 }
@@ -31,32 +33,32 @@ impl Glitch {
 impl Filter for Glitch {
     fn transform_buffer(
         &mut self,
+        context: &FrameContext,
         buffer: &mut Vec<crate::Pixel>,
         restore: &mut Option<Vec<crate::Pixel>>,
+        offset: &mut (i32, i32),
     ) {
-        if self.index % 4 == 0 {
-            self.seed += 1;
-        }
-        self.index += 1;
-        let mut rng = StdRng::seed_from_u64(self.seed);
+        let step = context.elapsed.as_millis() / GLITCH_INTERVAL.as_millis();
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(step as u64));
 
         let mut last_y = 0;
-        let mut offset = PRESET[rng.random::<u8>() as usize % PRESET.len()] * self.factor;
+        let mut shift = PRESET[rng.random::<u8>() as usize % PRESET.len()] * self.factor;
 
         for px in buffer {
             if px.y > last_y {
                 last_y = px.y;
-                if rng.random_bool(1.0 / self.factor as f64) {
-                    offset = PRESET[rng.random::<u8>() as usize % PRESET.len()] * self.factor;
+                if rng.random_bool(1.0 / self.factor.max(1) as f64) {
+                    shift = PRESET[rng.random::<u8>() as usize % PRESET.len()] * self.factor;
                 }
             }
-            let val = px.x as i32 + offset;
-            if val >= 0 && val < self.screen_x as i32 {
+            let val = px.x as i32 + shift;
+            let screen_val = val + context.image_area.origin_x as i32 + offset.0;
+            if val >= 0 && screen_val >= 0 && screen_val < context.canvas_size.0 as i32 {
                 px.x = val as u32;
 
                 if let Some(restore) = restore {
-                    if offset < 0 && px.edges.has_edge(Edge::Left) {
-                        for i in 0..offset.abs() {
+                    if shift < 0 && px.edges.has_edge(Edge::Left) {
+                        for i in 0..shift.abs() {
                             restore.push(Pixel {
                                 x: px.x + i as u32,
                                 y: px.y,
@@ -64,10 +66,10 @@ impl Filter for Glitch {
                                 edges: Edges::default(),
                             });
                         }
-                    } else if offset > 0 && px.edges.has_edge(Edge::Right) {
-                        for i in 0..offset {
+                    } else if shift > 0 && px.edges.has_edge(Edge::Right) {
+                        for i in 0..shift {
                             restore.push(Pixel {
-                                x: px.x - i as u32,
+                                x: px.x.wrapping_sub(i as u32),
                                 y: px.y,
                                 value: Rgba::from(RESTORE_DEBUG_COLOR),
                                 edges: Edges::default(),
@@ -78,4 +80,11 @@ impl Filter for Glitch {
             }
         }
     }
+
+    fn set_param(&mut self, name: &str, value: f64) {
+        if name == "factor" {
+            // also the inverse chance of a new shift per line
+            self.factor = value.max(1.0) as i32;
+        }
+    }
 }
@@ -1,17 +1,50 @@
+mod animate;
 mod blend;
 mod bounce;
 mod glitch;
 mod rainbow;
 
+pub use animate::{Animated, Curve, Easing, Wave};
 pub use blend::Blend;
 pub use bounce::Bounce;
 pub use glitch::Glitch;
 pub use rainbow::Rainbow;
 
-pub trait Filter {
+use std::time::Duration;
+
+use crate::Area;
+
+/// What a filter knows about the frame it transforms. Filters animate based on this
+/// instead of counting calls, so they move at the same speed at any frame rate.
+#[derive(Debug, Clone)]
+pub struct FrameContext {
+    /// Time since flooding started
+    pub elapsed: Duration,
+    /// Time since the previous frame, zero for the first one
+    pub delta: Duration,
+    /// Frames transformed since flooding started
+    pub frame_index: usize,
+    /// Position of the frame within the source in seconds
+    pub timestamp: f32,
+    /// Canvas of the primary server
+    pub canvas_size: (u32, u32),
+    /// Where the image sits on the primary server
+    pub image_area: Area,
+}
+
+/// Filters are shown with their `Debug` representation on the dashboard.
+pub trait Filter: std::fmt::Debug {
+    /// `offset` is the translation of the whole image. Filters that only move the image
+    /// should change it instead of rewriting every pixel in `buffer`.
     fn transform_buffer(
         &mut self,
+        context: &FrameContext,
         buffer: &mut Vec<crate::Pixel>,
         restore: &mut Option<Vec<crate::Pixel>>,
+        offset: &mut (i32, i32),
     );
+
+    /// Sets the numeric parameter `name`, used to animate it. Values are clamped to what
+    /// the parameter accepts, unknown names are ignored.
+    fn set_param(&mut self, _name: &str, _value: f64) {}
 }
@@ -1,19 +1,21 @@
-use super::Filter;
+use super::{Filter, FrameContext};
 use hsl::HSL;
 use image::{Pixel, Rgba};
 
+#[derive(Debug)]
 pub struct Rainbow {
     alpha: u8,
-    speed: usize,
-    frame: usize,
+    /// Degrees per second
+    speed: f32,
+    hue: f32,
 }
 
 impl Rainbow {
-    pub fn new(alpha: u8, speed: usize) -> Self {
+    pub fn new(alpha: u8, speed: f32) -> Self {
         Self {
             alpha,
             speed,
-            frame: 0,
+            hue: 0.0,
         }
     }
 }
@@ -21,12 +23,15 @@ impl Rainbow {
 impl Filter for Rainbow {
     fn transform_buffer(
         &mut self,
+        context: &FrameContext,
         buffer: &mut Vec<crate::Pixel>,
         _restore: &mut Option<Vec<crate::Pixel>>,
+        _offset: &mut (i32, i32),
     ) {
-        let hue = (self.frame * self.speed) % 360;
+        // advanced step by step, so a changing speed changes how fast the hue moves from here on
+        self.hue = (self.hue + context.delta.as_secs_f32() * self.speed).rem_euclid(360.0);
         let mask = HSL {
-            h: hue as f64,
+            h: self.hue as f64,
             s: 1.0,
             l: 0.5,
         }
@@ -36,7 +41,75 @@ impl Filter for Rainbow {
             px.value
                 .blend(&Rgba::from([mask.0, mask.1, mask.2, self.alpha]));
         }
+    }
+
+    fn set_param(&mut self, name: &str, value: f64) {
+        match name {
+            "alpha" => self.alpha = value.clamp(0.0, 255.0) as u8,
+            "speed" => self.speed = value as f32,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{edges::Edges, Area};
+
+    /// Tints a black pixel with the frame at `elapsed`, `delta` after the previous one.
+    fn tint(rainbow: &mut Rainbow, elapsed: Duration, delta: Duration) -> Rgba<u8> {
+        let context = FrameContext {
+            elapsed,
+            delta,
+            frame_index: 0,
+            timestamp: 0.0,
+            canvas_size: (1, 1),
+            image_area: Area {
+                origin_x: 0,
+                origin_y: 0,
+                size_x: 1,
+                size_y: 1,
+            },
+        };
+        let mut buffer = vec![crate::Pixel {
+            x: 0,
+            y: 0,
+            value: Rgba([0, 0, 0, 0xff]),
+            edges: Edges::default(),
+        }];
+        rainbow.transform_buffer(&context, &mut buffer, &mut None, &mut (0, 0));
+        buffer[0].value
+    }
+
+    #[test]
+    fn hue_follows_time_not_frames() {
+        let mut slow = Rainbow::new(0xff, 90.0);
+        let mut fast = Rainbow::new(0xff, 90.0);
+
+        let second = Duration::from_secs(1);
+        let slow_tint = tint(&mut slow, second, second);
+        let mut fast_tint = Rgba([0; 4]);
+        for i in 1..=64 {
+            fast_tint = tint(&mut fast, second * i / 64, second / 64);
+        }
+
+        assert_eq!(slow_tint, fast_tint);
+        assert_ne!(slow_tint, tint(&mut slow, 2 * second, second));
+    }
+
+    #[test]
+    fn changing_speed_does_not_make_the_hue_jump() {
+        let mut rainbow = Rainbow::new(0xff, 90.0);
+        let second = Duration::from_secs(1);
+        let mut last = Rgba([0; 4]);
+        for i in 1..=100 {
+            last = tint(&mut rainbow, second * i, second);
+        }
 
-        self.frame += 1;
+        rainbow.set_param("speed", 91.0);
+        assert_eq!(tint(&mut rainbow, second * 100, Duration::ZERO), last);
     }
 }
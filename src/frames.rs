@@ -0,0 +1,569 @@
+use std::{
+    collections::HashMap,
+    io::{stdout, Write},
+    path::PathBuf,
+    str::FromStr,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use ffmpeg_sidecar::{child::FfmpegChild, command::FfmpegCommand, event::FfmpegEvent};
+use image::Rgba;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    cache,
+    edges::{Edge, Edges},
+    Area, Pixel, RESTORE_DEBUG_COLOR,
+};
+
+/// Maps the coordinates of a frame's pixels to their index in the buffer.
+type Lookup = HashMap<(u32, u32), usize>;
+
+/// A decoded and preprocessed frame of the source.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Presentation time in seconds since the start of the source
+    pub timestamp: f32,
+    pub pixels: Vec<Pixel>,
+    /// Pixels that are not occupied anymore in the next frame
+    pub restore: Vec<Pixel>,
+    /// Indices of the pixels that changed since the previous frame
+    pub delta: Vec<usize>,
+}
+
+impl Frame {
+    /// Builds a frame from raw RGBA data, dropping fully transparent pixels.
+    pub(crate) fn decode(timestamp: f32, width: u32, data: &[u8]) -> Result<(Self, Lookup)> {
+        let mut pixels = Vec::with_capacity(data.len() / 4);
+        let mut lookup = HashMap::with_capacity(data.len() / 4);
+
+        for (i, pixel) in data.chunks(4).enumerate() {
+            let x = i as u32 % width;
+            let y = i as u32 / width;
+
+            if pixel[3] != 0 {
+                pixels.push(Pixel {
+                    x,
+                    y,
+                    value: Rgba::from([pixel[0], pixel[1], pixel[2], pixel[3]]),
+                    edges: Edges::default(),
+                });
+                lookup.insert((x, y), pixels.len() - 1);
+            }
+        }
+
+        calc_edges(&mut pixels)?;
+
+        let frame = Self {
+            timestamp,
+            pixels,
+            restore: Vec::new(),
+            delta: Vec::new(),
+        };
+        Ok((frame, lookup))
+    }
+}
+
+/// What to decode and how to preprocess it.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub file: String,
+    /// ffmpeg filtergraph applied while decoding, see [`Resize::filtergraph`]
+    pub filtergraph: Option<String>,
+    /// Whether restore sets are computed
+    pub restore: bool,
+    /// Whether loading progress is printed to stdout
+    pub progress: bool,
+}
+
+/// How the source is fitted into the target size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Fit {
+    /// Scale to fit inside, keeping the aspect ratio
+    Contain,
+    /// Scale to fill the whole target, keeping the aspect ratio and cropping the rest
+    Cover,
+    /// Scale to exactly the target size
+    Stretch,
+}
+
+/// A rectangle cut out of the source, written as `<w>x<h>+<x>+<y>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid crop '{s}', expected <w>x<h>+<x>+<y>");
+
+        let (size, position) = s.split_once('+').unwrap_or((s, "0+0"));
+        let (width, height) = size.split_once('x').ok_or_else(err)?;
+        let (x, y) = position.split_once('+').ok_or_else(err)?;
+
+        Ok(Self {
+            width: width.parse().map_err(|_| err())?,
+            height: height.parse().map_err(|_| err())?,
+            x: x.parse().map_err(|_| err())?,
+            y: y.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+/// Cropping and scaling applied to the source before it is turned into pixels.
+#[derive(Debug, Clone, Default)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub scale: Option<f32>,
+    pub fit: Option<Fit>,
+    pub crop: Option<Crop>,
+}
+
+impl Resize {
+    /// The ffmpeg filtergraph implementing the resize, `None` if the source is used as is.
+    ///
+    /// `bounds` is the space available on the canvas, used by `fit` for every dimension
+    /// that isn't given explicitly.
+    pub fn filtergraph(&self, bounds: (u32, u32)) -> Option<String> {
+        let mut filters = Vec::new();
+
+        if let Some(crop) = self.crop {
+            filters.push(format!(
+                "crop={}:{}:{}:{}",
+                crop.width, crop.height, crop.x, crop.y
+            ));
+        }
+
+        if let Some(scale) = self.scale {
+            filters.push(format!("scale=trunc(iw*{scale}):trunc(ih*{scale})"));
+        }
+
+        match (self.fit, self.width, self.height) {
+            (None, None, None) => (),
+            // -1 keeps the aspect ratio
+            (None, width, height) => filters.push(format!(
+                "scale={}:{}",
+                width.map_or(-1, |w| w as i64),
+                height.map_or(-1, |h| h as i64)
+            )),
+            (Some(fit), width, height) => {
+                let width = width.unwrap_or(bounds.0);
+                let height = height.unwrap_or(bounds.1);
+                filters.push(match fit {
+                    Fit::Contain => {
+                        format!("scale={width}:{height}:force_original_aspect_ratio=decrease")
+                    }
+                    Fit::Cover => format!(
+                        "scale={width}:{height}:force_original_aspect_ratio=increase,crop={width}:{height}"
+                    ),
+                    Fit::Stretch => format!("scale={width}:{height}"),
+                });
+            }
+        }
+
+        (!filters.is_empty()).then(|| filters.join(","))
+    }
+}
+
+/// The space left on a canvas of `canvas_size` right of and below `origin`.
+pub fn bounds(canvas_size: (u32, u32), origin: (u32, u32)) -> (u32, u32) {
+    (
+        canvas_size.0.saturating_sub(origin.0),
+        canvas_size.1.saturating_sub(origin.1),
+    )
+}
+
+/// How sources are opened, the same for every source played during a run.
+#[derive(Debug, Clone, Default)]
+pub struct SourceOptions {
+    pub resize: Resize,
+    pub restore: bool,
+    /// Decode while flooding with this many frames buffered, instead of preloading
+    pub stream: Option<usize>,
+    pub cache_dir: Option<PathBuf>,
+    /// Whether loading progress is printed to stdout, off once the terminal belongs to the
+    /// dashboard
+    pub progress: bool,
+}
+
+impl SourceOptions {
+    /// Opens `file`, with `bounds` being the space available on the canvas. Returns the
+    /// frame source together with the size of the frames.
+    pub async fn open(&self, file: &str, bounds: (u32, u32)) -> Result<(FrameSource, (u32, u32))> {
+        let source = Source {
+            file: file.to_string(),
+            filtergraph: self.resize.filtergraph(bounds),
+            restore: self.restore,
+            progress: self.progress,
+        };
+
+        if let Some(capacity) = self.stream {
+            let (source, size) = stream(source, capacity).await?;
+            if self.progress {
+                println!("Streaming frames from {file}");
+            }
+            return Ok((source, size));
+        }
+
+        let cache_dir = self.cache_dir.clone();
+        let (frames, size) = tokio::task::spawn_blocking(move || match cache_dir {
+            Some(dir) => cache::load_or_insert(&dir, &source),
+            None => load(&source),
+        })
+        .await??;
+        if self.progress {
+            println!("Preprocessed {} frames successfully", frames.len());
+        }
+        Ok((FrameSource::Preloaded { frames, next: 0 }, size))
+    }
+}
+
+/// Where the render loop takes its frames from.
+pub enum FrameSource {
+    /// All frames decoded up front, played in a loop
+    Preloaded { frames: Vec<Frame>, next: usize },
+    /// Frames decoded ahead by a background thread
+    Streaming(mpsc::Receiver<Frame>),
+}
+
+impl FrameSource {
+    /// The next frame to show, `None` once the source has nothing left to play.
+    pub async fn next(&mut self) -> Option<Frame> {
+        match self {
+            FrameSource::Preloaded { frames, next } => {
+                let frame = frames.get(*next)?.clone();
+                *next = (*next + 1) % frames.len();
+                Some(frame)
+            }
+            FrameSource::Streaming(rx) => rx.recv().await,
+        }
+    }
+
+    /// Number of frames in the source, `None` if it isn't known up front.
+    pub fn frame_count(&self) -> Option<usize> {
+        match self {
+            FrameSource::Preloaded { frames, .. } => Some(frames.len()),
+            FrameSource::Streaming(_) => None,
+        }
+    }
+}
+
+fn spawn_decoder(source: &Source) -> Result<FfmpegChild> {
+    let mut command = FfmpegCommand::new();
+    command.hide_banner().input(&source.file);
+    if let Some(filtergraph) = &source.filtergraph {
+        command.filter(filtergraph);
+    }
+
+    Ok(command
+        .args("-f rawvideo -pix_fmt rgba -".split(' '))
+        .spawn()?)
+}
+
+/// Decodes `source` and preprocesses all of its frames, returning them with the source size.
+pub fn load(source: &Source) -> Result<(Vec<Frame>, (u32, u32))> {
+    let mut decoder = spawn_decoder(source)?;
+
+    let mut size = (0, 0);
+    let mut frames = Vec::new();
+    let mut lookups = Vec::new();
+    for event in decoder.iter()? {
+        if let FfmpegEvent::OutputFrame(frame) = event {
+            if source.progress {
+                print!("\rLoading frame {}...", frame.frame_num);
+                stdout().flush()?;
+            }
+
+            size = (frame.width, frame.height);
+
+            let (frame, lookup) = Frame::decode(frame.timestamp, frame.width, &frame.data)?;
+            frames.push(frame);
+            lookups.push(lookup);
+        }
+    }
+
+    let num_frames = frames.len();
+    if source.progress {
+        println!("\rLoading {num_frames} frames... success");
+    }
+
+    // the source loops, so the last frame is followed by the first one
+    for i in 0..num_frames {
+        let next = (i + 1) % num_frames;
+        let prev = (i + num_frames - 1) % num_frames;
+
+        if source.restore {
+            frames[i].restore = restore_set(&frames[i], &lookups[next]);
+        }
+        frames[i].delta = delta(&frames[i], &frames[prev], &lookups[prev]);
+    }
+
+    Ok((frames, size))
+}
+
+/// Decodes `source` on a background thread, keeping up to `capacity` preprocessed frames
+/// ready. The decoder is restarted whenever it reaches the end, so the source loops.
+///
+/// Returns once the first frame is decoded, together with the source size.
+pub async fn stream(source: Source, capacity: usize) -> Result<(FrameSource, (u32, u32))> {
+    let (tx, rx) = mpsc::channel(capacity);
+    let (size_tx, size_rx) = oneshot::channel();
+    let name = source.file.clone();
+
+    thread::spawn(move || {
+        let mut size_tx = Some(size_tx);
+        // the latest frame, held back until its successor is known for the restore set
+        let mut last: Option<(Frame, Lookup)> = None;
+
+        loop {
+            let Ok(mut decoder) = spawn_decoder(&source) else {
+                return;
+            };
+            let Ok(events) = decoder.iter() else {
+                return;
+            };
+
+            let mut decoded = 0;
+            for event in events {
+                let FfmpegEvent::OutputFrame(frame) = event else {
+                    continue;
+                };
+                decoded += 1;
+
+                if let Some(size_tx) = size_tx.take() {
+                    let _ = size_tx.send((frame.width, frame.height));
+                }
+
+                let Ok((mut frame, lookup)) =
+                    Frame::decode(frame.timestamp, frame.width, &frame.data)
+                else {
+                    return;
+                };
+
+                frame.delta = match &last {
+                    Some((prev, prev_lookup)) => delta(&frame, prev, prev_lookup),
+                    None => (0..frame.pixels.len()).collect(),
+                };
+
+                if let Some((mut prev, _)) = last.replace((frame, lookup)) {
+                    if source.restore {
+                        let (_, next_lookup) = last.as_ref().unwrap();
+                        prev.restore = restore_set(&prev, next_lookup);
+                    }
+
+                    // the render loop is gone
+                    if tx.blocking_send(prev).is_err() {
+                        return;
+                    }
+                }
+            }
+
+            // don't spin on sources that stopped producing frames
+            if decoded == 0 {
+                return;
+            }
+        }
+    });
+
+    let size = size_rx
+        .await
+        .map_err(|_| anyhow!("Failed to decode a frame from {name}"))?;
+    Ok((FrameSource::Streaming(rx), size))
+}
+
+/// The pixels of `frame` that are not occupied by the frame described by `next`.
+fn restore_set(frame: &Frame, next: &Lookup) -> Vec<Pixel> {
+    frame
+        .pixels
+        .iter()
+        .filter(|px| px.value[3] != 0 && !next.contains_key(&(px.x, px.y)))
+        .map(|px| Pixel {
+            x: px.x,
+            y: px.y,
+            value: Rgba::from(RESTORE_DEBUG_COLOR),
+            edges: Edges::default(),
+        })
+        .collect()
+}
+
+/// Indices of the pixels of `frame` that are new or have a different color than in `prev`.
+fn delta(frame: &Frame, prev: &Frame, prev_lookup: &Lookup) -> Vec<usize> {
+    frame
+        .pixels
+        .iter()
+        .enumerate()
+        .filter(|(_, px)| match prev_lookup.get(&(px.x, px.y)) {
+            Some(&i) => prev.pixels[i].value != px.value,
+            None => true,
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn calc_edges(buffer: &mut [Pixel]) -> Result<()> {
+    let mut area = Area {
+        origin_x: u32::MAX,
+        origin_y: u32::MAX,
+        size_x: 0,
+        size_y: 0,
+    };
+    for px in buffer.iter() {
+        if px.x > area.size_x {
+            area.size_x = px.x;
+        }
+        if px.y > area.size_y {
+            area.size_y = px.y;
+        }
+    }
+
+    let mut temp: Vec<Vec<Option<usize>>> =
+        vec![vec![None; area.size_y as usize + 1]; area.size_x as usize + 1];
+    for (i, px) in buffer.iter().enumerate() {
+        temp[px.x as usize][px.y as usize] = Some(i);
+    }
+
+    let mut edges = Vec::with_capacity(4);
+    for (x, line) in temp.iter().enumerate() {
+        for (y, i) in line.iter().enumerate() {
+            if let Some(i) = i {
+                edges.clear();
+
+                if y == 0 || temp[x][y - 1].is_none() {
+                    edges.push(Edge::Top);
+                }
+
+                if x == area.size_x as usize || temp[x + 1][y].is_none() {
+                    edges.push(Edge::Right);
+                }
+
+                if y == area.size_y as usize || temp[x][y + 1].is_none() {
+                    edges.push(Edge::Bottom);
+                }
+
+                if x == 0 || temp[x - 1][y].is_none() {
+                    edges.push(Edge::Left);
+                }
+
+                buffer[*i].edges = Edges::new(edges.as_slice());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_crops() {
+        assert_eq!(
+            "64x32+10+5".parse(),
+            Ok(Crop {
+                width: 64,
+                height: 32,
+                x: 10,
+                y: 5,
+            })
+        );
+        assert_eq!(
+            "64x32".parse(),
+            Ok(Crop {
+                width: 64,
+                height: 32,
+                x: 0,
+                y: 0,
+            })
+        );
+        assert!("64x32+10".parse::<Crop>().is_err());
+        assert!("64+10+5".parse::<Crop>().is_err());
+        assert!("64x-1+0+0".parse::<Crop>().is_err());
+    }
+
+    #[test]
+    fn builds_filtergraphs() {
+        let bounds = (100, 50);
+        assert_eq!(Resize::default().filtergraph(bounds), None);
+
+        let resize = Resize {
+            width: Some(64),
+            crop: Some(Crop {
+                width: 20,
+                height: 10,
+                x: 1,
+                y: 2,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            resize.filtergraph(bounds).unwrap(),
+            "crop=20:10:1:2,scale=64:-1"
+        );
+
+        // fit takes whatever isn't given from the bounds
+        let resize = Resize {
+            height: Some(30),
+            fit: Some(Fit::Contain),
+            ..Default::default()
+        };
+        assert_eq!(
+            resize.filtergraph(bounds).unwrap(),
+            "scale=100:30:force_original_aspect_ratio=decrease"
+        );
+
+        let resize = Resize {
+            scale: Some(0.5),
+            fit: Some(Fit::Cover),
+            ..Default::default()
+        };
+        assert_eq!(
+            resize.filtergraph(bounds).unwrap(),
+            "scale=trunc(iw*0.5):trunc(ih*0.5),\
+             scale=100:50:force_original_aspect_ratio=increase,crop=100:50"
+        );
+    }
+
+    /// A 2x2 frame, `None` being a transparent pixel.
+    fn frame(colors: [Option<u8>; 4]) -> (Frame, Lookup) {
+        let data: Vec<u8> = colors
+            .iter()
+            .flat_map(|color| match color {
+                Some(red) => [*red, 0, 0, 0xff],
+                None => [0; 4],
+            })
+            .collect();
+        Frame::decode(0.0, 2, &data).unwrap()
+    }
+
+    #[test]
+    fn finds_changed_pixels() {
+        let (prev, prev_lookup) = frame([Some(1), Some(2), None, Some(4)]);
+        let (next, _) = frame([Some(1), Some(9), Some(3), Some(4)]);
+
+        // the changed pixel at (1, 0) and the new one at (0, 1)
+        assert_eq!(delta(&next, &prev, &prev_lookup), [1, 2]);
+        assert!(delta(&prev, &prev, &prev_lookup).is_empty());
+    }
+
+    #[test]
+    fn restores_pixels_the_next_frame_leaves_free() {
+        let (current, _) = frame([Some(1), Some(2), None, Some(4)]);
+        let (_, next_lookup) = frame([Some(1), None, Some(3), None]);
+
+        let restore: Vec<_> = restore_set(&current, &next_lookup)
+            .iter()
+            .map(|px| (px.x, px.y, px.value))
+            .collect();
+        let color = Rgba::from(RESTORE_DEBUG_COLOR);
+        assert_eq!(restore, [(1, 0, color), (1, 1, color)]);
+    }
+}
@@ -0,0 +1,88 @@
+pub mod api;
+pub mod cache;
+pub mod conn;
+pub mod control;
+pub mod display;
+pub mod edges;
+pub mod filter;
+pub mod frames;
+pub mod limit;
+pub mod metrics;
+pub mod order;
+pub mod server;
+pub mod settings;
+pub mod shadow;
+
+use image::Rgba;
+
+use crate::{
+    conn::{Partition, Protocol, ReconnectPolicy},
+    edges::Edges,
+    limit::Limits,
+    order::Order,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pixel {
+    pub x: u32,
+    pub y: u32,
+    pub value: Rgba<u8>,
+    pub edges: Edges,
+}
+
+const RESTORE_DEBUG_COLOR: [u8; 4] = [0, 0, 0, 0xff];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Area {
+    pub origin_x: u32,
+    pub origin_y: u32,
+    pub size_x: u32,
+    pub size_y: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server: String,
+    pub threads: usize,
+    pub restore: bool,
+    pub protocol: Protocol,
+    pub offset_command: bool,
+    pub order: Order,
+    pub partition: Partition,
+    pub limits: Limits,
+    pub defend: bool,
+    pub reconnect: ReconnectPolicy,
+    pub canvas_size: (u32, u32),
+    pub image_area: Area,
+}
+
+#[cfg(test)]
+impl Config {
+    /// A config for flooding `server` in tests: three connections sending text commands,
+    /// and a 6x4 image at (5, 7).
+    pub(crate) fn local(server: String, canvas_size: (u32, u32)) -> Self {
+        Self {
+            server,
+            threads: 3,
+            restore: false,
+            protocol: Protocol::Text,
+            offset_command: false,
+            order: Order::default(),
+            partition: Partition::default(),
+            limits: Limits::default(),
+            defend: false,
+            reconnect: ReconnectPolicy {
+                initial_backoff: std::time::Duration::from_millis(10),
+                max_backoff: std::time::Duration::from_millis(100),
+                max_retries: None,
+            },
+            canvas_size,
+            image_area: Area {
+                origin_x: 5,
+                origin_y: 7,
+                size_x: 6,
+                size_y: 4,
+            },
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Mutex;
+
+use tokio::time::{sleep, Duration, Instant};
+
+/// Pixel and byte rates a bundle must not exceed, summed over all of its connections.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub max_pps: Option<u32>,
+    /// Bytes per second
+    pub max_bandwidth: Option<u64>,
+}
+
+/// A token bucket holding up to one second worth of tokens.
+///
+/// Taking more tokens than available puts the bucket into debt, and the caller waits
+/// until it is paid off. This way chunks larger than the bucket still go through, just
+/// not faster than the rate.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    async fn take(&self, tokens: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (available, last) = &mut *state;
+
+            let now = Instant::now();
+            *available =
+                (*available + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+            *last = now;
+            *available -= tokens as f64;
+
+            (*available < 0.0).then(|| Duration::from_secs_f64(-*available / self.rate))
+        };
+
+        if let Some(wait) = wait {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Shared by the connections of a bundle to stay within its [`Limits`].
+#[derive(Debug)]
+pub struct RateLimiter {
+    pixels: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            pixels: limits.max_pps.map(|pps| TokenBucket::new(pps as f64)),
+            bytes: limits
+                .max_bandwidth
+                .map(|bandwidth| TokenBucket::new(bandwidth as f64)),
+        }
+    }
+
+    /// Waits until `pixels` pixels taking up `bytes` bytes may be sent.
+    pub async fn acquire(&self, pixels: usize, bytes: usize) {
+        if let Some(bucket) = &self.pixels {
+            bucket.take(pixels).await;
+        }
+        if let Some(bucket) = &self.bytes {
+            bucket.take(bytes).await;
+        }
+    }
+}
+
+/// Parses a byte rate like `250k`, `10M` or `1G`, using decimal prefixes.
+pub fn parse_bandwidth(s: &str) -> Result<u64, String> {
+    let err = || format!("invalid bandwidth '{s}', expected e.g. 500k, 10M or 1G");
+
+    let (number, factor) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1e3),
+        Some((i, 'M')) => (&s[..i], 1e6),
+        Some((i, 'G')) => (&s[..i], 1e9),
+        _ => (s, 1.0),
+    };
+    let number: f64 = number.parse().map_err(|_| err())?;
+
+    // a rate of zero would never let anything through
+    let bandwidth = (number * factor) as u64;
+    if !number.is_finite() || bandwidth < 1 {
+        return Err(err());
+    }
+    Ok(bandwidth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_once_the_burst_is_used_up() {
+        let limiter = RateLimiter::new(Limits {
+            max_pps: Some(1000),
+            max_bandwidth: None,
+        });
+
+        let start = Instant::now();
+        limiter.acquire(1000, 0).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        limiter.acquire(500, 0).await;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn parses_bandwidth() {
+        assert_eq!(parse_bandwidth("1500"), Ok(1500));
+        assert_eq!(parse_bandwidth("500k"), Ok(500_000));
+        assert_eq!(parse_bandwidth("2.5M"), Ok(2_500_000));
+        assert_eq!(parse_bandwidth("1G"), Ok(1_000_000_000));
+        assert!(parse_bandwidth("fast").is_err());
+        assert!(parse_bandwidth("0").is_err());
+        assert!(parse_bandwidth("0.5").is_err());
+        assert!(parse_bandwidth("-1k").is_err());
+    }
+}
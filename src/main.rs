@@ -1,61 +1,40 @@
-mod conn;
-mod edges;
-mod filter;
-
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use ffmpeg_sidecar::{command::FfmpegCommand, event::FfmpegEvent};
-use image::Rgba;
-use std::{collections::HashMap, time::Duration};
+use clap::{parser::ValueSource, value_parser, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
-    io::{stdout, AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::mpsc,
-    time::{interval, Instant},
+    sync::{mpsc, watch},
+    time::{interval, sleep, timeout, Instant},
 };
 
-use crate::{
-    conn::{ConnectionBundle, Stats},
-    edges::{Edge, Edges},
-    filter::{Blend, Bounce, Filter, Glitch, Rainbow},
+use c3pixelflut::{
+    api::{self, ApiState},
+    conn::{ConnectionBundle, Partition, Protocol, ReconnectPolicy, Target},
+    control::{Control, FilterStatus, Status},
+    display::{start_display, Display, DisplayInfo, ServerInfo},
+    filter::{Filter, FrameContext},
+    frames::{bounds, Crop, Fit, Resize, SourceOptions},
+    limit::{parse_bandwidth, Limits, RateLimiter},
+    metrics::{self, Metrics},
+    order::Order,
+    settings::{parse_color, FilterKind, FilterSpec, Settings, RAINBOW_SPEED},
+    shadow::Shadow,
+    Area, Config,
 };
 
-#[derive(Debug, Clone, Copy)]
-pub struct Pixel {
-    x: u32,
-    y: u32,
-    value: Rgba<u8>,
-    edges: Edges,
-}
-
-const RESTORE_DEBUG_COLOR: [u8; 4] = [0, 0, 0, 0xff];
-
-async fn start_display(threads: usize) -> Result<mpsc::UnboundedSender<Stats>> {
-    let (tx, mut rx) = mpsc::unbounded_channel();
-
-    tokio::spawn(async move {
-        let mut errors = 0;
-
-        loop {
-            let stats: Stats = rx.recv().await.unwrap();
-            errors += stats.errors;
-
-            stdout()
-                .write(format!("\rThreads: {threads}  |  Errors: {errors}").as_bytes())
-                .await
-                .unwrap();
-            stdout().flush().await.unwrap();
-        }
-    });
-
-    Ok(tx)
-}
+/// Snapshot interval in milliseconds used by defend mode if none is given.
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 500;
 
 async fn fetch_canvas_size(server: &str) -> Result<(u32, u32)> {
     let (mut rx, mut tx) = TcpStream::connect(&server).await?.into_split();
 
     let mut answer = String::new();
-    tx.write(b"SIZE\n").await?;
+    tx.write_all(b"SIZE\n").await?;
     loop {
         let mut byte = [0];
         if rx.read(&mut byte).await? != 1 {
@@ -84,89 +63,476 @@ async fn fetch_canvas_size(server: &str) -> Result<(u32, u32)> {
     Ok((x, y))
 }
 
-fn calc_edges(buffer: &mut Vec<Pixel>) -> Result<()> {
-    let mut area = Area {
-        origin_x: u32::MAX,
-        origin_y: u32::MAX,
-        size_x: 0,
-        size_y: 0,
-    };
-    for px in buffer.iter() {
-        if px.x > area.size_x {
-            area.size_x = px.x;
+/// Time to wait for further HELP output before considering the answer complete.
+const HELP_TIMEOUT: Duration = Duration::from_millis(500);
+
+async fn fetch_help(server: &str) -> Result<String> {
+    let (mut rx, mut tx) = TcpStream::connect(&server).await?.into_split();
+
+    tx.write_all(b"HELP\n").await?;
+
+    // HELP answers have no defined length, so read until the server stops sending
+    let mut answer = Vec::new();
+    let mut buf = [0; 1024];
+    while let Ok(read) = timeout(HELP_TIMEOUT, rx.read(&mut buf)).await {
+        let read = read?;
+        if read == 0 {
+            break;
         }
-        if px.y > area.size_y {
-            area.size_y = px.y;
+        answer.extend_from_slice(&buf[..read]);
+    }
+
+    Ok(String::from_utf8_lossy(&answer).into_owned())
+}
+
+/// Checks the requested optional protocol features against the server's HELP answer.
+///
+/// Returns the protocol and whether the OFFSET command can be used, falling back to
+/// plain absolute PX commands for everything the server doesn't advertise.
+async fn negotiate_features(
+    server: &str,
+    protocol: Protocol,
+    offset_command: bool,
+) -> Result<(Protocol, bool)> {
+    if protocol == Protocol::Text && !offset_command {
+        return Ok((protocol, offset_command));
+    }
+
+    let help = fetch_help(server).await?;
+    let supports = |command: &str| help.split_whitespace().any(|word| word == command);
+
+    let protocol = if protocol == Protocol::Binary && !supports("PB") {
+        println!("Server does not support the binary protocol, falling back to text");
+        Protocol::Text
+    } else {
+        protocol
+    };
+
+    let offset_command = if offset_command && !supports("OFFSET") {
+        println!("Server does not support the OFFSET command, sending absolute coordinates");
+        false
+    } else {
+        offset_command
+    };
+
+    Ok((protocol, offset_command))
+}
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The image origin on every server.
+fn origins(args: &Args) -> Vec<(u32, u32)> {
+    args.servers
+        .iter()
+        .map(|target| {
+            (
+                target.offset_x.or(args.offset_x).unwrap_or_default(),
+                target.offset_y.or(args.offset_y).unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+/// Filters together with the specs they were built from.
+type Filters = Vec<(FilterSpec, Box<dyn Filter>)>;
+
+fn build_filters(specs: &[FilterSpec]) -> Result<Filters> {
+    specs
+        .iter()
+        .map(|spec| Ok((spec.clone(), spec.build()?)))
+        .collect()
+}
+
+/// Changes `filters` over to `specs`. Filters whose spec is still there are kept, so
+/// their animations and state carry on, only new specs are built. `filters` stays as it
+/// is if building fails.
+fn rebuild_filters(filters: &mut Filters, specs: &[FilterSpec]) -> Result<()> {
+    let mut taken = vec![false; filters.len()];
+    let kept: Vec<Option<usize>> = specs
+        .iter()
+        .map(|spec| {
+            let index = (0..filters.len()).find(|&i| !taken[i] && filters[i].0 == *spec)?;
+            taken[index] = true;
+            Some(index)
+        })
+        .collect();
+
+    let mut built = specs
+        .iter()
+        .zip(&kept)
+        .filter(|(_, kept)| kept.is_none())
+        .map(|(spec, _)| spec.build())
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+
+    let mut old: Vec<_> = filters.drain(..).map(Some).collect();
+    *filters = specs
+        .iter()
+        .zip(kept)
+        .map(|(spec, kept)| match kept {
+            Some(index) => old[index].take().unwrap(),
+            None => (spec.clone(), built.next().unwrap()),
+        })
+        .collect();
+    Ok(())
+}
+
+/// The canvas region the shadow of a server has to cover.
+fn shadow_region(config: &Config, specs: &[FilterSpec]) -> Area {
+    // a bouncing image can end up anywhere on the canvas
+    let bounce = specs
+        .iter()
+        .any(|spec| matches!(spec.kind, FilterKind::Bounce { .. }));
+    if bounce {
+        Area {
+            origin_x: 0,
+            origin_y: 0,
+            size_x: config.canvas_size.0,
+            size_y: config.canvas_size.1,
         }
+    } else {
+        config.image_area.clone()
     }
+}
+
+/// The specs of the enabled filters.
+fn active(specs: &[FilterSpec], enabled: &[bool]) -> Vec<FilterSpec> {
+    specs
+        .iter()
+        .zip(enabled)
+        .filter(|(_, enabled)| **enabled)
+        .map(|(spec, _)| spec.clone())
+        .collect()
+}
 
-    let mut temp: Vec<Vec<Option<usize>>> =
-        vec![vec![None; area.size_y as usize + 1]; area.size_x as usize + 1];
-    for (i, px) in buffer.iter().enumerate() {
-        temp[px.x as usize][px.y as usize] = Some(i);
+fn status(
+    file: &str,
+    configs: &[Config],
+    specs: &[FilterSpec],
+    enabled: &[bool],
+    paused: bool,
+) -> Status {
+    Status {
+        file: file.to_string(),
+        origins: configs
+            .iter()
+            .map(|config| (config.image_area.origin_x, config.image_area.origin_y))
+            .collect(),
+        filters: specs
+            .iter()
+            .zip(enabled)
+            .map(|(spec, enabled)| FilterStatus {
+                spec: spec.clone(),
+                enabled: *enabled,
+            })
+            .collect(),
+        paused,
     }
+}
 
-    let mut edges = Vec::with_capacity(4);
-    for (x, line) in temp.iter().enumerate() {
-        for (y, i) in line.iter().enumerate() {
-            if let Some(i) = i {
-                edges.clear();
+fn describe(filters: &Filters) -> Vec<String> {
+    filters
+        .iter()
+        .map(|(_, filter)| format!("{filter:?}"))
+        .collect()
+}
 
-                if y == 0 || temp[x][y - 1].is_none() {
-                    edges.push(Edge::Top);
-                }
+/// The parts of the config that can change while flooding.
+struct Watched {
+    file: String,
+    specs: Vec<FilterSpec>,
+    origins: Vec<(u32, u32)>,
+}
+
+/// The command line as given, the config is merged into it on every reload.
+struct CommandLine {
+    matches: ArgMatches,
+    args: Args,
+}
 
-                if x == area.size_x as usize || temp[x + 1][y].is_none() {
-                    edges.push(Edge::Right);
+/// Polls the config at `path` and sends whatever changed to the render loop.
+///
+/// Filters, offsets and the source file are reloaded, everything else, including the
+/// list of servers, only takes effect on restart.
+fn watch_config(
+    path: PathBuf,
+    cli: CommandLine,
+    mut current: Watched,
+    options: SourceOptions,
+    canvas_size: (u32, u32),
+    control_tx: mpsc::UnboundedSender<Control>,
+    display: Display,
+) {
+    let modified = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path);
+        loop {
+            sleep(WATCH_INTERVAL).await;
+
+            let now_modified = modified(&path);
+            if now_modified == last_modified {
+                continue;
+            }
+            last_modified = now_modified;
+
+            let settings = match Settings::load(&path) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    display.set_message(format!("Not reloading: {e}"));
+                    continue;
+                }
+            };
+            let mut args = cli.args.clone();
+            let specs = apply_settings(&mut args, &cli.matches, &settings)
+                .and_then(|()| filter_specs(&args))
+                .map(|cli_specs| [settings.filters, cli_specs].concat());
+            let specs = match specs {
+                Ok(specs) => specs,
+                Err(e) => {
+                    display.set_message(format!("Not reloading: {e}"));
+                    continue;
                 }
+            };
 
-                if y == area.size_y as usize || temp[x][y + 1].is_none() {
-                    edges.push(Edge::Bottom);
+            let mut controls = Vec::new();
+
+            let origins = origins(&args);
+            for (server, (origin, current)) in origins.iter().zip(&current.origins).enumerate() {
+                if origin != current {
+                    controls.push(Control::Origin {
+                        server: Some(server),
+                        x: origin.0,
+                        y: origin.1,
+                    });
                 }
+            }
 
-                if x == 0 || temp[x - 1][y].is_none() {
-                    edges.push(Edge::Left);
+            if let Some(file) = args.file.filter(|file| *file != current.file) {
+                let bounds = bounds(canvas_size, origins.first().copied().unwrap_or_default());
+                match options.open(&file, bounds).await {
+                    Ok((source, size)) => {
+                        controls.push(Control::Source {
+                            name: file.clone(),
+                            source,
+                            size,
+                        });
+                        current.file = file;
+                    }
+                    Err(e) => display.set_message(format!("Failed to open {file}: {e}")),
                 }
+            }
 
-                buffer[*i].edges = Edges::new(edges.as_slice());
+            if specs != current.specs {
+                controls.push(Control::Filters(specs.clone()));
+            }
+
+            current.specs = specs;
+            current.origins = origins;
+            for control in controls {
+                if control_tx.send(control).is_err() {
+                    return;
+                }
             }
         }
+    });
+}
+
+/// Overrides everything `settings` sets unless it was given on the command line.
+fn apply_settings(args: &mut Args, matches: &ArgMatches, settings: &Settings) -> Result<()> {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    if !settings.server.is_empty() && !from_cli("servers") {
+        args.servers = settings
+            .server
+            .iter()
+            .map(|server| server.parse().map_err(|e: String| anyhow!(e)))
+            .collect::<Result<_>>()?;
     }
+    if let (Some(port), false) = (settings.port, from_cli("port")) {
+        args.port = port;
+    }
+    if let (Some(threads), false) = (settings.threads, from_cli("threads")) {
+        args.threads = threads;
+    }
+    if let (Some(restore), false) = (settings.restore, from_cli("restore")) {
+        args.restore = restore;
+    }
+    args.file = args.file.take().or(settings.file.clone());
+    args.offset_x = args.offset_x.or(settings.x);
+    args.offset_y = args.offset_y.or(settings.y);
+    args.target_fps = args.target_fps.or(settings.target_fps);
 
     Ok(())
 }
 
-#[derive(Parser)]
+/// The filters given as command line flags, which always run in this order after the
+/// pipeline from the config file.
+fn filter_specs(args: &Args) -> Result<Vec<FilterSpec>> {
+    let mut specs = Vec::new();
+
+    if let Some(alpha) = &args.rainbow {
+        specs.push(
+            FilterKind::Rainbow {
+                alpha: u8::from_str_radix(alpha, 16)?,
+                speed: RAINBOW_SPEED,
+            }
+            .into(),
+        );
+    }
+
+    if let Some(speed) = args.bounce {
+        specs.push(FilterKind::Bounce { speed }.into());
+    }
+
+    if let Some(color) = &args.blend {
+        // validated early instead of when the pipeline is built
+        parse_color(color)?;
+        specs.push(
+            FilterKind::Blend {
+                color: color.clone(),
+            }
+            .into(),
+        );
+    }
+
+    if let Some(factor) = args.glitch {
+        specs.push(
+            FilterKind::Glitch {
+                factor: factor as i32,
+            }
+            .into(),
+        );
+    }
+
+    Ok(specs)
+}
+
+#[derive(Parser, Clone)]
 struct Args {
-    /// The servers address
+    /// The servers address, repeat as <ADDRESS>[:PORT][,threads=N][,x=PX][,y=PX] to flood
+    /// several servers at once
     #[arg(
         short = 's',
-        long,
+        long = "server",
         value_name = "ADDRESS",
         default_value = "wall.c3pixelflut.de"
     )]
-    server: String,
+    servers: Vec<Target>,
 
     /// The servers port
     #[arg(short = 'p', long, default_value_t = 1337)]
     port: u16,
 
-    /// The amount of threads (concurrent connections) that should be used
+    /// The amount of threads (concurrent connections) that should be used per server
     #[arg(short = 't', long, value_name = "NUM", default_value_t = 12)]
     threads: usize,
 
+    /// The wire protocol used for setting pixels
+    #[arg(long, value_enum, default_value_t = Protocol::Text)]
+    protocol: Protocol,
+
+    /// Delay before reconnecting a failed connection, doubled with every failed attempt
+    #[arg(long, value_name = "MS", default_value_t = 100)]
+    reconnect_backoff: u64,
+
+    /// Upper bound for the reconnect delay
+    #[arg(long, value_name = "MS", default_value_t = 10_000)]
+    reconnect_max_backoff: u64,
+
+    /// Gives a connection up after <NUM> failed attempts in a row instead of retrying forever
+    #[arg(long, value_name = "NUM")]
+    reconnect_max_retries: Option<u32>,
+
     /// The file to load the base image / video from
     #[arg(short = 'f', long)]
-    file: String,
+    file: Option<String>,
+
+    /// Loads settings and the filter pipeline from a TOML file, options given here win
+    #[arg(short = 'c', long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Scales the source to <PX> pixels wide
+    #[arg(long, value_name = "PX")]
+    width: Option<u32>,
+
+    /// Scales the source to <PX> pixels high
+    #[arg(long, value_name = "PX")]
+    height: Option<u32>,
+
+    /// Scales the source by <FACTOR>
+    #[arg(long, value_name = "FACTOR")]
+    scale: Option<f32>,
+
+    /// Fits the source into --width/--height, or the canvas space right of and below the offset
+    #[arg(long, value_enum)]
+    fit: Option<Fit>,
+
+    /// Crops the source to <W>x<H>+<X>+<Y> before scaling
+    #[arg(long, value_name = "GEOMETRY")]
+    crop: Option<Crop>,
+
+    /// Decodes the source while flooding instead of loading every frame up front
+    #[arg(long)]
+    stream: bool,
+
+    /// The amount of frames decoded ahead in stream mode
+    #[arg(long, value_name = "FRAMES", default_value_t = 64)]
+    stream_buffer: usize,
+
+    /// Caches preprocessed frames in <DIR>, so later runs can skip decoding
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<String>,
 
     /// The targeted animation and video fps
     #[arg(long, value_name = "FPS")]
     target_fps: Option<u32>,
 
+    /// Serves Prometheus metrics on http://<ADDR>/metrics
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+
+    /// Serves the control API on http://<ADDR>, see the README for the endpoints
+    #[arg(long, value_name = "ADDR")]
+    control_listen: Option<String>,
+
+    /// Positions the image with the OFFSET command instead of sending absolute coordinates
+    #[arg(long)]
+    offset_command: bool,
+
+    /// The order in which the pixels of a frame are sent
+    #[arg(long, value_enum, default_value_t)]
+    order: Order,
+
+    /// How the pixels are split across the connections
+    #[arg(long, value_enum, default_value_t)]
+    partition: Partition,
+
+    /// Maximum pixels per second sent over all connections, reads included
+    #[arg(long, value_name = "PPS", value_parser = value_parser!(u32).range(1..))]
+    max_pps: Option<u32>,
+
+    /// Maximum bytes per second sent over all connections, e.g. 500k or 10M
+    #[arg(long, value_name = "BYTES", value_parser = parse_bandwidth)]
+    max_bandwidth: Option<u64>,
+
     /// Restores pixels after they are not occupied anymore
     #[arg(short = 'r', long)]
     restore: bool,
 
+    /// Keeps a local snapshot of the canvas under the image, refreshed every <MS> milliseconds
+    #[arg(long, value_name = "MS")]
+    snapshot_interval: Option<u64>,
+
+    /// Only re-sends pixels that differ on the canvas snapshot, winning contested regions
+    #[arg(long)]
+    defend: bool,
+
     /// Offset of the image on the x axis
     #[arg(short = 'x', value_name = "PX")]
     offset_x: Option<u32>,
@@ -192,186 +558,397 @@ struct Args {
     glitch: Option<u32>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Area {
-    origin_x: u32,
-    origin_y: u32,
-    size_x: u32,
-    size_y: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct Config {
-    pub server: String,
-    pub threads: usize,
-    pub restore: bool,
-    pub canvas_size: (u32, u32),
-    pub image_area: Area,
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let cli = Args::from_arg_matches(&matches)?;
+    let mut args = cli.clone();
+
+    let mut specs = Vec::new();
+    if let Some(path) = &args.config {
+        let settings = Settings::load(path)?;
+        apply_settings(&mut args, &matches, &settings)?;
+        specs = settings.filters;
+    }
+    specs.extend(filter_specs(&args)?);
+
+    let Some(mut file) = args.file.clone() else {
+        return Err(anyhow!("No file given, use --file or set it in the config"));
+    };
+
+    // the first server is the primary one, the image is sized and filtered for its canvas
+    let mut targets = Vec::new();
+    for (target, origin) in args.servers.iter().zip(origins(&args)) {
+        let server = format!("{}:{}", target.address, target.port.unwrap_or(args.port));
+        let canvas_size = fetch_canvas_size(&server).await?;
+        let (protocol, offset_command) =
+            negotiate_features(&server, args.protocol, args.offset_command).await?;
+        targets.push((
+            target,
+            origin,
+            server,
+            canvas_size,
+            protocol,
+            offset_command,
+        ));
+    }
+    let (_, origin, _, canvas_size, _, _) = targets[0];
+
+    let options = SourceOptions {
+        resize: Resize {
+            width: args.width,
+            height: args.height,
+            scale: args.scale,
+            fit: args.fit,
+            crop: args.crop,
+        },
+        restore: args.restore,
+        stream: args.stream.then_some(args.stream_buffer),
+        cache_dir: args.cache_dir.clone().map(PathBuf::from),
+        progress: true,
+    };
+    let (mut source, (width, height)) = options.open(&file, bounds(canvas_size, origin)).await?;
 
-    let server = format!("{server}:{port}", server = args.server, port = args.port);
-    let canvas_size = fetch_canvas_size(&server).await?;
+    let mut configs: Vec<Config> = targets
+        .into_iter()
+        .map(
+            |(target, origin, server, canvas_size, protocol, offset_command)| Config {
+                server,
+                threads: target.threads.unwrap_or(args.threads),
+                restore: args.restore,
+                protocol,
+                offset_command,
+                order: args.order,
+                partition: args.partition,
+                limits: Limits {
+                    max_pps: args.max_pps,
+                    max_bandwidth: args.max_bandwidth,
+                },
+                defend: args.defend,
+                reconnect: ReconnectPolicy {
+                    initial_backoff: Duration::from_millis(args.reconnect_backoff),
+                    max_backoff: Duration::from_millis(args.reconnect_max_backoff),
+                    max_retries: args.reconnect_max_retries,
+                },
+                canvas_size,
+                image_area: Area {
+                    origin_x: origin.0,
+                    origin_y: origin.1,
+                    size_x: width,
+                    size_y: height,
+                },
+            },
+        )
+        .collect();
+
+    let mut filters = build_filters(&specs)?;
 
-    let display_tx = start_display(args.threads).await?;
+    // defend mode can't work without knowing what the canvas looks like
+    let snapshot_interval = if args.defend {
+        Some(args.snapshot_interval.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL))
+    } else {
+        args.snapshot_interval
+    };
 
-    let mut decoder = FfmpegCommand::new()
-        .hide_banner()
-        .input(&args.file)
-        .args("-f rawvideo -pix_fmt rgba -".split(' '))
-        .spawn()?;
+    // the limits hold for everything sent to a server
+    let limiters: Vec<Arc<RateLimiter>> = configs
+        .iter()
+        .map(|config| Arc::new(RateLimiter::new(config.limits)))
+        .collect();
 
-    let (mut width, mut height) = (0, 0);
+    let shadows: Vec<Option<Shadow>> = configs
+        .iter()
+        .zip(&limiters)
+        .map(|(config, limiter)| {
+            snapshot_interval.map(|ms| {
+                Shadow::spawn(
+                    config,
+                    shadow_region(config, &specs),
+                    Duration::from_millis(ms),
+                    limiter.clone(),
+                )
+            })
+        })
+        .collect();
 
-    let mut frames: Vec<(f32, Vec<Pixel>, Vec<Pixel>, HashMap<(u32, u32), usize>)> = Vec::new();
-    for event in decoder.iter()? {
-        match event {
-            FfmpegEvent::OutputFrame(frame) => {
-                print!("\rLoading frame {}...", frame.frame_num);
-                stdout().flush().await?;
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = &args.metrics_listen {
+        metrics::serve(addr, metrics.clone()).await?;
+    }
 
-                width = frame.width;
-                height = frame.height;
+    let display = start_display(
+        DisplayInfo {
+            servers: configs
+                .iter()
+                .map(|config| ServerInfo {
+                    address: config.server.clone(),
+                    canvas_size: config.canvas_size,
+                    threads: config.threads,
+                })
+                .collect(),
+        },
+        metrics.clone(),
+    )
+    .await?;
+    display.set_source(&file, (width, height));
+    display.set_filters(describe(&filters));
+
+    let mut bundles = Vec::new();
+    for (i, ((config, shadow), limiter)) in configs.iter().zip(&shadows).zip(limiters).enumerate() {
+        bundles.push(
+            ConnectionBundle::new(config.clone(), display.stats_tx(i), shadow.clone(), limiter)
+                .await?,
+        );
+        println!(
+            "Starting to flood {width}x{height} source on {}x{} canvas [{}]",
+            config.canvas_size.0, config.canvas_size.1, config.server
+        );
+    }
 
-                let mut frame_vec = Vec::with_capacity((width * height) as usize);
-                let mut frame_lookup = HashMap::with_capacity((width * height) as usize);
+    let mut enabled = vec![true; specs.len()];
+    let mut paused = false;
 
-                for (i, pixel) in frame.data.chunks(4).enumerate() {
-                    let x = (i as u32 % frame.width) + args.offset_x.unwrap_or_default();
-                    let y = (i as u32 / frame.width) + args.offset_y.unwrap_or_default();
+    // sources opened from here on load while the dashboard is drawn
+    let options = SourceOptions {
+        progress: false,
+        ..options
+    };
 
-                    if pixel[3] != 0 {
-                        frame_vec.push(Pixel {
-                            x,
-                            y,
-                            value: Rgba::from([pixel[0], pixel[1], pixel[2], pixel[3]]),
-                            edges: Edges::default(),
-                        });
-                        frame_lookup.insert((x, y), frame_vec.len() - 1);
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    let (status_tx, status_rx) = watch::channel(status(&file, &configs, &specs, &enabled, paused));
+    if let Some(addr) = &args.control_listen {
+        api::serve(
+            addr,
+            ApiState {
+                control_tx: control_tx.clone(),
+                status: status_rx,
+                stats: display.stats_broadcast(),
+                options: options.clone(),
+                canvas_size,
+            },
+        )
+        .await?;
+    }
+    if let Some(path) = args.config.clone() {
+        watch_config(
+            path,
+            CommandLine { matches, args: cli },
+            Watched {
+                file: file.clone(),
+                specs: specs.clone(),
+                origins: origins(&args),
+            },
+            options,
+            canvas_size,
+            control_tx,
+            display.clone(),
+        );
+    }
+
+    let mut interval = args
+        .target_fps
+        .map(|fps| interval(Duration::from_secs_f64(1.0 / fps as f64)));
+
+    let mut timer = Instant::now();
+    let mut last_timestamp = f32::INFINITY;
+
+    // unlike the timer these keep going when the source starts over
+    let started = Instant::now();
+    let mut last_elapsed = Duration::ZERO;
+    let mut frames_transformed = 0;
+
+    let mut frame_count = source.frame_count();
+    let mut frame_index = 0;
+
+    while let Some(frame) = source.next().await {
+        let mut changed = false;
+        let mut rebuild = false;
+        while let Ok(control) = control_rx.try_recv() {
+            changed = true;
+            match control {
+                Control::Filters(new_specs) => {
+                    enabled = vec![true; new_specs.len()];
+                    specs = new_specs;
+                    rebuild = true;
+                }
+                Control::Filter { index, spec } => {
+                    if let Some(old) = specs.get_mut(index) {
+                        *old = spec;
+                        rebuild = true;
                     }
                 }
+                Control::EnableFilter { index, enabled: on } => {
+                    if let Some(old) = enabled.get_mut(index) {
+                        *old = on;
+                        rebuild = true;
+                    }
+                }
+                Control::Origin { server, x, y } => {
+                    for (i, (config, bundle)) in configs.iter_mut().zip(&bundles).enumerate() {
+                        if server.is_none_or(|server| server == i) {
+                            config.image_area.origin_x = x;
+                            config.image_area.origin_y = y;
+                            bundle.set_origin(x, y)?;
+                        }
+                    }
+                }
+                Control::Source {
+                    name,
+                    source: new_source,
+                    size,
+                } => {
+                    source = new_source;
+                    frame_count = source.frame_count();
+                    last_timestamp = f32::INFINITY;
+                    for config in configs.iter_mut() {
+                        config.image_area.size_x = size.0;
+                        config.image_area.size_y = size.1;
+                    }
+                    display.set_source(&name, size);
+                    file = name;
+                }
+                Control::Pause => {
+                    paused = true;
+                    for bundle in &bundles {
+                        bundle.pause()?;
+                    }
+                }
+                Control::Resume => {
+                    paused = false;
+                    for bundle in &bundles {
+                        bundle.resume()?;
+                    }
+                }
+            }
+        }
 
-                calc_edges(&mut frame_vec)?;
-                frames.push((frame.timestamp, frame_vec, Vec::new(), frame_lookup));
+        if rebuild {
+            match rebuild_filters(&mut filters, &active(&specs, &enabled)) {
+                Ok(()) => display.set_filters(describe(&filters)),
+                Err(e) => display.set_message(format!("Failed to build filters: {e}")),
             }
-            // FfmpegEvent::Log(_level, log) => println!("[ffmpeg] {log}"),
-            _ => (),
         }
-    }
+        if changed {
+            // the shadows follow the image around
+            let active = active(&specs, &enabled);
+            for (config, shadow) in configs.iter().zip(&shadows) {
+                if let Some(shadow) = shadow {
+                    shadow.set_region(shadow_region(config, &active));
+                }
+            }
+            status_tx.send_replace(status(&file, &configs, &specs, &enabled, paused));
+        }
 
-    let num_frames = frames.len();
-    println!("\rLoading {num_frames} frames... success");
-
-    // Iterate over
-    if args.restore {
-        for i in 0..num_frames {
-            let (frame, next) = if i == num_frames - 1 {
-                let split = frames.split_at_mut(1);
-                let len = split.1.len();
-                (&mut split.1[len - 1], &mut split.0[0])
-            } else {
-                let split = frames.split_at_mut(i + 1);
-                (&mut split.0[i], &mut split.1[0])
-            };
+        // the source started over
+        if frame.timestamp <= last_timestamp {
+            timer = Instant::now();
+            frame_index = 0;
+        }
+        last_timestamp = frame.timestamp;
+
+        let lag = if let Some(interval) = &mut interval {
+            interval.tick().await.elapsed()
+        } else {
+            let due = Duration::from_secs_f32(frame.timestamp);
+            let lag = timer.elapsed().saturating_sub(due);
+            tokio::time::sleep(due.saturating_sub(timer.elapsed())).await;
+            lag
+        };
+        metrics.record_iteration(lag);
+
+        let num_pixels = frame.pixels.len();
+        let mut buffer = frame.pixels;
+        let mut restore = if args.restore {
+            Some(frame.restore)
+        } else {
+            None
+        };
+        let elapsed = started.elapsed();
+        let context = FrameContext {
+            elapsed,
+            delta: elapsed.saturating_sub(last_elapsed),
+            frame_index: frames_transformed,
+            timestamp: frame.timestamp,
+            canvas_size: configs[0].canvas_size,
+            image_area: configs[0].image_area.clone(),
+        };
+        last_elapsed = elapsed;
+        frames_transformed += 1;
+
+        let mut offset = (0, 0);
+        for (_, filter) in filters.iter_mut() {
+            filter.transform_buffer(&context, &mut buffer, &mut restore, &mut offset);
+        }
 
-            let buf = &frame.1;
+        // delta indices are only meaningful as long as no filter added or removed pixels
+        let delta = (buffer.len() == num_pixels).then_some(frame.delta);
 
-            for px in buf.iter() {
-                if px.value[3] == 0 || next.3.contains_key(&(px.x, px.y)) {
-                    continue;
-                }
+        display.frame(frame_index, frame_count, &buffer, offset);
+        frame_index += 1;
 
-                frame.2.push(Pixel {
-                    x: px.x,
-                    y: px.y,
-                    value: Rgba::from(RESTORE_DEBUG_COLOR),
-                    edges: Edges::default(),
-                });
-            }
+        // the primary bundle gets the original, every other one a copy
+        for bundle in bundles.iter().skip(1) {
+            bundle.update_buffer(buffer.clone(), restore.clone(), delta.clone(), offset)?;
         }
+        bundles[0].update_buffer(buffer, restore, delta, offset)?;
     }
 
-    let frames: Vec<(f32, Vec<Pixel>, Vec<Pixel>)> = frames
-        .into_iter()
-        .map(|(time, data, restore, _lookup)| (time, data, restore))
-        .collect();
-
-    println!("Preprocessed {} frames successfully", num_frames);
-
-    let config = Config {
-        server,
-        threads: args.threads,
-        restore: args.restore,
-        canvas_size,
-        image_area: Area {
-            origin_x: args.offset_x.unwrap_or_default(),
-            origin_y: args.offset_y.unwrap_or_default(),
-            size_x: width,
-            size_y: height,
-        },
-    };
+    Err(anyhow!("The source ran out of frames"))
+}
 
-    let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use c3pixelflut::server::Server;
 
-    if let Some(alpha) = args.rainbow {
-        filters.push(Box::new(Rainbow::new(u8::from_str_radix(&alpha, 16)?, 10)));
+    #[tokio::test]
+    async fn fetches_canvas_size() {
+        let server = Server::local(320, 240, false).await.unwrap();
+        assert_eq!(fetch_canvas_size(&server.addr()).await.unwrap(), (320, 240));
     }
 
-    if let Some(speed) = args.bounce {
-        filters.push(Box::new(Bounce::new(&config, speed)));
+    #[tokio::test]
+    async fn negotiates_supported_features() {
+        let server = Server::local(32, 24, true).await.unwrap();
+        let features = negotiate_features(&server.addr(), Protocol::Binary, true).await;
+        assert_eq!(features.unwrap(), (Protocol::Binary, true));
     }
 
-    if let Some(color) = args.blend {
-        let mut buf = [0; 4];
-        for i in 0..4 {
-            let idx = i * 2;
-            buf[i] = u8::from_str_radix(&color[idx..(idx + 2)], 16)?;
-        }
-        filters.push(Box::new(Blend::new(image::Rgba::from(buf))));
+    #[tokio::test]
+    async fn falls_back_to_text_protocol() {
+        let server = Server::local(32, 24, false).await.unwrap();
+        let features = negotiate_features(&server.addr(), Protocol::Binary, true).await;
+        assert_eq!(features.unwrap(), (Protocol::Text, true));
     }
 
-    if let Some(factor) = args.glitch {
-        filters.push(Box::new(Glitch::new(&config, factor as i32)));
+    #[test]
+    fn rejects_zero_rates() {
+        for (flag, value) in [("--max-pps", "0"), ("--max-bandwidth", "0.5")] {
+            assert!(Args::try_parse_from(["c3pixelflut", flag, value]).is_err());
+        }
+        assert!(Args::try_parse_from(["c3pixelflut", "--max-pps", "1"]).is_ok());
     }
 
-    let connection = ConnectionBundle::new(config.clone(), display_tx.clone()).await?;
-
-    println!(
-        "Starting to flood {width}x{height} source on {}x{} canvas [{}]",
-        canvas_size.0, canvas_size.1, config.server
-    );
-
-    let mut interval = if let Some(fps) = args.target_fps {
-        Some(interval(Duration::from_secs_f64(1.0 / fps as f64)))
-    } else {
-        None
-    };
-
-    loop {
-        let timer = Instant::now();
-
-        for (t, frame, res) in frames.iter() {
-            if let Some(interval) = &mut interval {
-                interval.tick().await;
-            } else {
-                let duration = Duration::from_secs_f32(*t).saturating_sub(timer.elapsed());
-                tokio::time::sleep(duration).await;
-            }
-
-            let mut buffer = frame.clone();
-            let mut restore = if args.restore {
-                Some(res.clone())
-            } else {
-                None
-            };
-            for filter in filters.iter_mut() {
-                filter.transform_buffer(&mut buffer, &mut restore);
-            }
-
-            connection.update_buffer(buffer, restore)?;
+    #[test]
+    fn keeps_filters_that_did_not_change() {
+        let bounce: FilterSpec = FilterKind::Bounce { speed: 3 }.into();
+        let glitch: FilterSpec = FilterKind::Glitch { factor: 4 }.into();
+        let mut filters = build_filters(&[bounce.clone(), glitch]).unwrap();
+        let instance =
+            |filters: &Filters, i: usize| &*filters[i].1 as *const dyn Filter as *const ();
+        let bouncing = instance(&filters, 0);
+
+        let rainbow: FilterSpec = FilterKind::Rainbow {
+            alpha: 128,
+            speed: RAINBOW_SPEED,
         }
+        .into();
+        rebuild_filters(&mut filters, &[rainbow.clone(), bounce.clone()]).unwrap();
+        assert_eq!(filters[0].0, rainbow);
+        assert_eq!(instance(&filters, 1), bouncing);
+
+        let broken: FilterSpec = FilterKind::Glitch { factor: 0 }.into();
+        assert!(rebuild_filters(&mut filters, &[broken]).is_err());
+        assert_eq!(filters.len(), 2);
     }
 }
@@ -0,0 +1,142 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use tokio::net::TcpListener;
+
+use crate::conn::Stats;
+
+/// Counters exported on the metrics endpoint, updated from the stats of the bundle and
+/// the render loop.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pixels: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    reconnects: AtomicU64,
+    frames: AtomicU64,
+    connections_up: AtomicU64,
+    iterations: AtomicU64,
+    /// How far the render loop was behind schedule on its last iteration, in microseconds
+    frame_lag: AtomicU64,
+}
+
+impl Metrics {
+    /// Records the stats of the bundle flooding the `server`th server.
+    pub fn record(&self, server: usize, stats: &Stats) {
+        self.pixels
+            .fetch_add(stats.pixels as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(stats.bytes as u64, Ordering::Relaxed);
+        self.errors
+            .fetch_add(stats.errors as u64, Ordering::Relaxed);
+        self.reconnects
+            .fetch_add(stats.reconnects as u64, Ordering::Relaxed);
+        // every server completes its own passes, count the first one only like the dashboard
+        if server == 0 {
+            self.frames
+                .fetch_add(stats.frames as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_connections_up(&self, up: usize) {
+        self.connections_up.store(up as u64, Ordering::Relaxed);
+    }
+
+    /// Records one pass of the render loop that started `lag` after the frame was due.
+    pub fn record_iteration(&self, lag: Duration) {
+        self.iterations.fetch_add(1, Ordering::Relaxed);
+        self.frame_lag
+            .store(lag.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP c3pixelflut_{name} {help}");
+            let _ = writeln!(out, "# TYPE c3pixelflut_{name} {kind}");
+            let _ = writeln!(out, "c3pixelflut_{name} {value}");
+        };
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        metric(
+            "pixels_total",
+            "counter",
+            "Pixels sent to the server",
+            load(&self.pixels),
+        );
+        metric(
+            "bytes_total",
+            "counter",
+            "Bytes sent to the server",
+            load(&self.bytes),
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Failed reads and writes",
+            load(&self.errors),
+        );
+        metric(
+            "reconnects_total",
+            "counter",
+            "Successful reconnects",
+            load(&self.reconnects),
+        );
+        metric(
+            "frames_total",
+            "counter",
+            "Completed passes over the frame buffer on the primary server",
+            load(&self.frames),
+        );
+        metric(
+            "connections_up",
+            "gauge",
+            "Connections currently connected",
+            load(&self.connections_up),
+        );
+        metric(
+            "loop_iterations_total",
+            "counter",
+            "Iterations of the render loop",
+            load(&self.iterations),
+        );
+        metric(
+            "frame_lag_seconds",
+            "gauge",
+            "How far the render loop was behind schedule on its last iteration",
+            (self.frame_lag.load(Ordering::Relaxed) as f64 / 1e6).to_string(),
+        );
+
+        out
+    }
+}
+
+async fn metrics(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
+
+/// Serves `metrics` on `http://<addr>/metrics`. Fails if `addr` can't be bound, serving
+/// happens in the background.
+pub async fn serve(addr: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let app = Router::new()
+        .route("/metrics", get(self::metrics))
+        .with_state(metrics);
+
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    Ok(())
+}
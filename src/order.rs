@@ -0,0 +1,147 @@
+use clap::ValueEnum;
+use rand::{random, seq::SliceRandom};
+
+use crate::Pixel;
+
+/// The order in which the pixels of a frame are sent.
+///
+/// Every connection follows the order for its own share of the pixels, so the image
+/// builds up in this order as a whole.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Order {
+    /// Line by line from the top, the order of the source
+    #[default]
+    Scanline,
+    /// Randomly shuffled on every frame
+    Shuffle,
+    /// Along a Hilbert curve, keeping consecutive pixels close together
+    Hilbert,
+    /// From the center outwards
+    Spiral,
+    /// Outlines first, then the inside
+    Edges,
+    /// Shuffled, but opaque and edge pixels tend to come first
+    Alpha,
+}
+
+impl Order {
+    /// Reorders `buffer` in place.
+    pub fn apply(&self, buffer: &mut [Pixel]) {
+        match self {
+            Order::Scanline => (),
+            Order::Shuffle => buffer.shuffle(&mut rand::rng()),
+            Order::Hilbert => {
+                let (width, height) = bounds(buffer);
+                let order = width.max(height).next_power_of_two();
+                buffer.sort_by_cached_key(|px| {
+                    let (x, y) = coords(px);
+                    hilbert(order, x as u32, y as u32)
+                });
+            }
+            Order::Spiral => {
+                let (width, height) = bounds(buffer);
+                let center = (width as f32 / 2.0, height as f32 / 2.0);
+                buffer.sort_by_cached_key(|px| {
+                    let (x, y) = coords(px);
+                    let (dx, dy) = (x as f32 - center.0, y as f32 - center.1);
+                    // rings of one pixel width, walked around by angle
+                    let ring = (dx * dx + dy * dy).sqrt() as u32;
+                    let angle = (dy.atan2(dx) * 1000.0) as i32;
+                    (ring, angle)
+                });
+            }
+            // stable, so the inside keeps its scanline order
+            Order::Edges => buffer.sort_by_key(|px| px.edges.bits() == 0),
+            Order::Alpha => {
+                // weighted random order: the smaller key of -ln(u) / weight tends to win
+                // for larger weights
+                buffer.sort_by_cached_key(|px| {
+                    let weight =
+                        px.value[3] as f32 / 255.0 + if px.edges.bits() != 0 { 1.0 } else { 0.0 };
+                    let u: f32 = random::<f32>().max(f32::MIN_POSITIVE);
+                    ((-u.ln() / weight.max(0.01)) * 1e6) as u64
+                });
+            }
+        }
+    }
+}
+
+/// Buffer coordinates, with pixels wrapped around to the left or top clamped to 0.
+fn coords(px: &Pixel) -> (i32, i32) {
+    ((px.x as i32).max(0), (px.y as i32).max(0))
+}
+
+/// Width and height of the box spanning all pixels of `buffer`.
+fn bounds(buffer: &[Pixel]) -> (u32, u32) {
+    buffer.iter().fold((1, 1), |(width, height), px| {
+        let (x, y) = coords(px);
+        (width.max(x as u32 + 1), height.max(y as u32 + 1))
+    })
+}
+
+/// Distance of `x`, `y` along the Hilbert curve filling an `order` x `order` square.
+fn hilbert(order: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = order / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+
+        // rotate the quadrant so the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = order - 1 - x;
+                y = order - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::edges::Edges;
+
+    fn buffer(width: u32, height: u32) -> Vec<Pixel> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| Pixel {
+                x,
+                y,
+                value: Rgba([0, 0, 0, 0xff]),
+                edges: Edges::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hilbert_only_steps_to_neighbours() {
+        let mut buffer = buffer(8, 8);
+        Order::Hilbert.apply(&mut buffer);
+
+        for pair in buffer.windows(2) {
+            let distance = pair[0].x.abs_diff(pair[1].x) + pair[0].y.abs_diff(pair[1].y);
+            assert_eq!(distance, 1);
+        }
+    }
+
+    #[test]
+    fn orders_keep_every_pixel() {
+        for order in Order::value_variants() {
+            let mut buffer = buffer(7, 5);
+            order.apply(&mut buffer);
+
+            let mut coords: Vec<_> = buffer.iter().map(|px| (px.y, px.x)).collect();
+            coords.sort();
+            assert_eq!(coords.len(), 35);
+            coords.dedup();
+            assert_eq!(coords.len(), 35, "{order:?} lost pixels");
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! A pixelflut server drawing into an in-memory canvas, used for local rehearsals and tests.
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use image::{Pixel as _, Rgba, RgbaImage};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::{interval, sleep_until, Instant},
+};
+
+use crate::{
+    conn::{parse_pixel, Protocol},
+    Pixel,
+};
+
+/// Color of every canvas pixel nobody painted yet.
+pub const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 0xff]);
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub canvas_size: (u32, u32),
+    /// Whether `PB` commands are accepted
+    pub binary: bool,
+    /// Maximum pixels per second a single connection may set, `None` for no limit
+    pub rate_limit: Option<u32>,
+}
+
+pub struct Server {
+    addr: SocketAddr,
+    canvas: Arc<Mutex<RgbaImage>>,
+}
+
+impl Server {
+    /// Binds to `addr` and starts accepting connections in the background.
+    pub async fn bind(addr: &str, config: ServerConfig) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let (width, height) = config.canvas_size;
+        let canvas = Arc::new(Mutex::new(RgbaImage::from_pixel(width, height, BACKGROUND)));
+
+        let shared = canvas.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, shared.clone(), config.clone()));
+            }
+        });
+
+        Ok(Self { addr, canvas })
+    }
+
+    /// Binds to a free port on localhost, for rehearsals next to the client and for tests.
+    pub async fn local(width: u32, height: u32, binary: bool) -> Result<Self> {
+        let config = ServerConfig {
+            canvas_size: (width, height),
+            binary,
+            rate_limit: None,
+        };
+        Self::bind("127.0.0.1:0", config).await
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// A copy of the current canvas.
+    pub fn canvas(&self) -> RgbaImage {
+        self.canvas.lock().unwrap().clone()
+    }
+
+    /// Saves the canvas as `<dir>/canvas-<unix time>.png` every `interval`.
+    pub fn snapshots(&self, dir: PathBuf, every: Duration) {
+        let canvas = self.canvas.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(every);
+            loop {
+                interval.tick().await;
+
+                let image = canvas.lock().unwrap().clone();
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let path = dir.join(format!("canvas-{time}.png"));
+
+                // encoding takes a while for large canvases
+                let res = tokio::task::spawn_blocking(move || image.save(&path)).await;
+                if let Ok(Err(e)) = res {
+                    println!("Failed to save snapshot: {e}");
+                }
+            }
+        });
+    }
+
+    /// A client config flooding this server, see [`crate::Config::local`].
+    #[cfg(test)]
+    pub(crate) fn config(&self) -> crate::Config {
+        crate::Config::local(self.addr(), self.canvas.lock().unwrap().dimensions())
+    }
+
+    /// Waits until the canvas equals `expected`, returning the last canvas seen.
+    #[cfg(test)]
+    pub async fn wait_for(&self, expected: &RgbaImage, timeout: Duration) -> RgbaImage {
+        let start = Instant::now();
+        loop {
+            let canvas = self.canvas();
+            if canvas == *expected || start.elapsed() > timeout {
+                return canvas;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Limits the pixels a connection sets to `pps` per second.
+struct RateLimit {
+    pps: u32,
+    window: Instant,
+    pixels: u32,
+}
+
+impl RateLimit {
+    fn new(pps: u32) -> Self {
+        Self {
+            pps,
+            window: Instant::now(),
+            pixels: 0,
+        }
+    }
+
+    /// Counts a pixel, waiting for the next second once the current one is used up.
+    async fn pixel(&mut self) {
+        self.pixels += 1;
+        if self.pixels >= self.pps {
+            sleep_until(self.window + Duration::from_secs(1)).await;
+            self.window = Instant::now();
+            self.pixels = 0;
+        }
+    }
+}
+
+async fn handle(stream: TcpStream, canvas: Arc<Mutex<RgbaImage>>, config: ServerConfig) {
+    let (rx, mut tx) = stream.into_split();
+    let mut rx = BufReader::new(rx);
+    let mut limit = config.rate_limit.map(RateLimit::new);
+    let mut offset = (0, 0);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        let Ok(first) = rx.read_u8().await else {
+            return;
+        };
+        line.push(first);
+
+        if config.binary && first == b'P' {
+            let Ok(second) = rx.read_u8().await else {
+                return;
+            };
+            line.push(second);
+
+            if second == b'B' {
+                let mut command = [0; 8];
+                if rx.read_exact(&mut command).await.is_err() {
+                    return;
+                }
+                set(&canvas, Protocol::decode_binary(command), offset);
+                if let Some(limit) = &mut limit {
+                    limit.pixel().await;
+                }
+                continue;
+            }
+        }
+
+        if line.last() != Some(&b'\n') && rx.read_until(b'\n', &mut line).await.is_err() {
+            return;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let answer = match parts.as_slice() {
+            ["SIZE"] => {
+                let canvas = canvas.lock().unwrap();
+                Some(format!("SIZE {} {}\n", canvas.width(), canvas.height()))
+            }
+            ["HELP"] => Some(if config.binary {
+                "HELP Commands: PX SIZE HELP OFFSET PB\n".to_string()
+            } else {
+                "HELP Commands: PX SIZE HELP OFFSET\n".to_string()
+            }),
+            ["OFFSET", x, y] => {
+                if let (Ok(x), Ok(y)) = (x.parse(), y.parse()) {
+                    offset = (x, y);
+                }
+                None
+            }
+            ["PX", x, y] => {
+                let (Ok(x), Ok(y)) = (x.parse::<u32>(), y.parse::<u32>()) else {
+                    continue;
+                };
+                let (x, y) = (x.saturating_add(offset.0), y.saturating_add(offset.1));
+                let canvas = canvas.lock().unwrap();
+                canvas.get_pixel_checked(x, y).map(|value| {
+                    format!(
+                        "PX {x} {y} {:02x}{:02x}{:02x}\n",
+                        value[0], value[1], value[2]
+                    )
+                })
+            }
+            ["PX", _, _, _] => {
+                if let Some(px) = parse_pixel(&line) {
+                    set(&canvas, px, offset);
+                    if let Some(limit) = &mut limit {
+                        limit.pixel().await;
+                    }
+                }
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(answer) = answer {
+            if tx.write_all(answer.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Blends `px` onto the canvas, ignoring pixels outside of it.
+fn set(canvas: &Mutex<RgbaImage>, px: Pixel, offset: (u32, u32)) {
+    let (x, y) = (px.x.saturating_add(offset.0), px.y.saturating_add(offset.1));
+    let mut canvas = canvas.lock().unwrap();
+    if x < canvas.width() && y < canvas.height() {
+        canvas.get_pixel_mut(x, y).blend(&px.value);
+    }
+}
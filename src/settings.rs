@@ -0,0 +1,292 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::{Animated, Blend, Bounce, Curve, Filter, Glitch, Rainbow};
+
+/// Settings loaded from a TOML file with `--config`. Options given on the command line
+/// take precedence over the file.
+///
+/// ```toml
+/// server = ["wall.c3pixelflut.de", "10.0.0.2:1234,threads=4"]
+/// file = "fit.png"
+/// x = 100
+/// restore = true
+///
+/// [[filter]]
+/// type = "bounce"
+/// speed = 3
+///
+/// [[filter]]
+/// type = "rainbow"
+/// alpha = 128
+/// speed = 60
+///
+/// [filter.animate.alpha]
+/// lfo = "sine"
+/// period = 2.0
+/// min = 64
+/// max = 255
+/// ```
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Server targets in the same format as `--server`
+    #[serde(default)]
+    pub server: Vec<String>,
+    pub port: Option<u16>,
+    pub threads: Option<usize>,
+    pub file: Option<String>,
+    pub x: Option<u32>,
+    pub y: Option<u32>,
+    pub target_fps: Option<u32>,
+    pub restore: Option<bool>,
+    /// The filter pipeline, applied in order
+    #[serde(default, rename = "filter")]
+    pub filters: Vec<FilterSpec>,
+}
+
+impl Settings {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+        toml::from_str(&content).map_err(|e| anyhow!("Invalid config {}: {e}", path.display()))
+    }
+}
+
+/// A filter, its parameters and how they change over time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterSpec {
+    #[serde(flatten)]
+    pub kind: FilterKind,
+    /// Curves driving numeric parameters, by parameter name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub animate: BTreeMap<String, Curve>,
+}
+
+impl From<FilterKind> for FilterSpec {
+    fn from(kind: FilterKind) -> Self {
+        Self {
+            kind,
+            animate: BTreeMap::new(),
+        }
+    }
+}
+
+/// A filter and its parameters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum FilterKind {
+    Rainbow {
+        alpha: u8,
+        /// Hue change per second in degrees
+        #[serde(default = "default_rainbow_speed")]
+        speed: f32,
+    },
+    Bounce {
+        speed: i8,
+    },
+    Blend {
+        /// `rrggbbaa`
+        color: String,
+    },
+    Glitch {
+        factor: i32,
+    },
+}
+
+/// Hue change of the rainbow filter in degrees per second, unless configured otherwise.
+pub const RAINBOW_SPEED: f32 = 120.0;
+
+fn default_rainbow_speed() -> f32 {
+    RAINBOW_SPEED
+}
+
+impl FilterKind {
+    /// Names of the parameters that can be animated.
+    pub fn params(&self) -> &'static [&'static str] {
+        match self {
+            FilterKind::Rainbow { .. } => &["alpha", "speed"],
+            FilterKind::Bounce { .. } => &["speed"],
+            FilterKind::Blend { .. } => &["red", "green", "blue", "alpha"],
+            FilterKind::Glitch { .. } => &["factor"],
+        }
+    }
+}
+
+impl FilterSpec {
+    /// Checks the parameters without building the filter.
+    pub fn validate(&self) -> Result<()> {
+        match &self.kind {
+            FilterKind::Blend { color } => {
+                parse_color(color)?;
+            }
+            FilterKind::Bounce { speed } if speed.unsigned_abs() > Bounce::MAX_SPEED as u8 => {
+                return Err(anyhow!(
+                    "Bounce speed must be between -{0} and {0}",
+                    Bounce::MAX_SPEED
+                ));
+            }
+            FilterKind::Glitch { factor } if *factor < 1 => {
+                return Err(anyhow!("Glitch factor must be at least 1"));
+            }
+            _ => (),
+        }
+        for (name, curve) in &self.animate {
+            if !self.kind.params().contains(&name.as_str()) {
+                return Err(anyhow!(
+                    "Can't animate '{name}', expected one of {}",
+                    self.kind.params().join(", ")
+                ));
+            }
+            curve
+                .validate()
+                .map_err(|e| anyhow!("Invalid animation of '{name}': {e}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> Result<Box<dyn Filter>> {
+        self.validate()?;
+        let filter: Box<dyn Filter> = match &self.kind {
+            FilterKind::Rainbow { alpha, speed } => Box::new(Rainbow::new(*alpha, *speed)),
+            FilterKind::Bounce { speed } => Box::new(Bounce::new(*speed)),
+            FilterKind::Blend { color } => Box::new(Blend::new(parse_color(color)?)),
+            FilterKind::Glitch { factor } => Box::new(Glitch::new(*factor)),
+        };
+
+        if self.animate.is_empty() {
+            return Ok(filter);
+        }
+        Ok(Box::new(Animated::new(filter, self.animate.clone())))
+    }
+}
+
+/// Parses a `rrggbbaa` color.
+pub fn parse_color(color: &str) -> Result<Rgba<u8>> {
+    if color.len() != 8 || !color.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid color '{color}', expected rrggbbaa"));
+    }
+
+    let mut buf = [0; 4];
+    for (i, value) in buf.iter_mut().enumerate() {
+        let idx = i * 2;
+        *value = u8::from_str_radix(&color[idx..(idx + 2)], 16)?;
+    }
+    Ok(Rgba::from(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filter_pipeline() {
+        let settings: Settings = toml::from_str(
+            r#"
+            server = ["wall.c3pixelflut.de"]
+            x = 100
+            restore = true
+
+            [[filter]]
+            type = "bounce"
+            speed = 3
+
+            [[filter]]
+            type = "rainbow"
+            alpha = 128
+
+            [[filter]]
+            type = "bounce"
+            speed = -1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.x, Some(100));
+        assert_eq!(settings.restore, Some(true));
+        assert_eq!(
+            settings.filters,
+            vec![
+                FilterKind::Bounce { speed: 3 }.into(),
+                FilterKind::Rainbow {
+                    alpha: 128,
+                    speed: RAINBOW_SPEED
+                }
+                .into(),
+                FilterKind::Bounce { speed: -1 }.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_animations() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [[filter]]
+            type = "rainbow"
+            alpha = 128
+
+            [filter.animate.alpha]
+            lfo = "square"
+            period = 2.0
+            min = 64
+            max = 255
+
+            [filter.animate.speed]
+            keyframes = [[0, 1], [2.5, 20]]
+            ease = "in-out"
+            "#,
+        )
+        .unwrap();
+
+        let spec = &settings.filters[0];
+        assert!(spec.validate().is_ok());
+        assert_eq!(spec.animate["alpha"].value(1.0), 255.0);
+        assert_eq!(spec.animate["speed"].value(5.0), 20.0);
+
+        let mut spec = spec.clone();
+        spec.animate
+            .insert("hue".to_string(), spec.animate["alpha"].clone());
+        assert!(spec.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_parameters_filters_cant_handle() {
+        let invalid = [
+            FilterKind::Glitch { factor: 0 },
+            FilterKind::Glitch { factor: -2 },
+            FilterKind::Bounce { speed: i8::MAX },
+            FilterKind::Bounce { speed: i8::MIN },
+            FilterKind::Blend {
+                color: "ff0é0000".to_string(),
+            },
+            FilterKind::Blend {
+                color: "+f00ff00".to_string(),
+            },
+        ];
+        for kind in invalid {
+            assert!(FilterSpec::from(kind.clone()).build().is_err(), "{kind:?}");
+        }
+
+        assert!(FilterSpec::from(FilterKind::Glitch { factor: 1 })
+            .build()
+            .is_ok());
+        assert!(FilterSpec::from(FilterKind::Bounce { speed: -3 })
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_filters() {
+        let settings = toml::from_str::<Settings>(
+            r#"
+            [[filter]]
+            type = "sparkle"
+            "#,
+        );
+        assert!(settings.is_err());
+    }
+}
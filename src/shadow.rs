@@ -0,0 +1,258 @@
+use std::{
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::Duration,
+};
+
+use anyhow::Result;
+use image::{Rgba, RgbaImage};
+use tokio::{sync::watch, time::sleep};
+
+use crate::{
+    conn::{connect, read_pixels, CommandBuffer},
+    limit::RateLimiter,
+    Area, Config, Pixel,
+};
+
+/// A local copy of a canvas region, refreshed by periodically reading it from the server.
+///
+/// Values can be up to one snapshot interval old, and of course contain our own pixels
+/// wherever we painted.
+#[derive(Clone)]
+pub struct Shadow {
+    snapshot: Arc<RwLock<Snapshot>>,
+    region: Arc<watch::Sender<Area>>,
+    canvas_size: (u32, u32),
+    generation: watch::Receiver<u64>,
+}
+
+struct Snapshot {
+    image: RgbaImage,
+    /// The region the image was sampled from, which lags behind [`Shadow::set_region`]
+    /// until the next snapshot
+    region: Area,
+}
+
+pub struct ShadowRead<'a> {
+    snapshot: RwLockReadGuard<'a, Snapshot>,
+}
+
+impl Shadow {
+    /// Starts sampling `region` (clamped to the canvas) every `interval`. The reads count
+    /// against `limiter` like the pixels of the bundle it is shared with.
+    pub fn spawn(
+        config: &Config,
+        region: Area,
+        interval: Duration,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        let (width, height) = config.canvas_size;
+        let snapshot = Arc::new(RwLock::new(Snapshot {
+            image: RgbaImage::new(width, height),
+            region: Area {
+                origin_x: 0,
+                origin_y: 0,
+                size_x: 0,
+                size_y: 0,
+            },
+        }));
+        let (generation_tx, generation) = watch::channel(0);
+        let (region_tx, region_rx) = watch::channel(clamp(region, config.canvas_size));
+
+        let server = config.server.clone();
+        let target = snapshot.clone();
+        tokio::spawn(async move {
+            let mut region = region_rx;
+            while !generation_tx.is_closed() {
+                // a failed snapshot is simply retried on a fresh connection
+                let _ = sample(
+                    &server,
+                    &mut region,
+                    &target,
+                    &generation_tx,
+                    interval,
+                    &limiter,
+                )
+                .await;
+                sleep(interval).await;
+            }
+        });
+
+        Self {
+            snapshot,
+            region: Arc::new(region_tx),
+            canvas_size: config.canvas_size,
+            generation,
+        }
+    }
+
+    /// Samples `region` (clamped to the canvas) from the next snapshot on, e.g. after the
+    /// image moved.
+    pub fn set_region(&self, region: Area) {
+        let region = clamp(region, self.canvas_size);
+        self.region.send_if_modified(|current| {
+            let modified = *current != region;
+            *current = region;
+            modified
+        });
+    }
+
+    /// Read access to the latest snapshot, `None` until the first one is complete.
+    pub fn read(&self) -> Option<ShadowRead<'_>> {
+        if *self.generation.borrow() == 0 {
+            return None;
+        }
+
+        Some(ShadowRead {
+            snapshot: self.snapshot.read().unwrap(),
+        })
+    }
+
+    /// Number of completed snapshots.
+    pub fn generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    /// Waits for the next snapshot.
+    pub async fn changed(&mut self) {
+        if self.generation.changed().await.is_err() {
+            // the sampler only stops once every shadow is dropped
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// The pixels of `target` (in canvas coordinates) that don't show up on the latest
+    /// snapshot. Everything counts as damaged until a snapshot is available.
+    pub fn damaged(&self, target: &[Pixel]) -> Vec<Pixel> {
+        let Some(snapshot) = self.read() else {
+            return target.to_vec();
+        };
+
+        target
+            .iter()
+            .filter(|px| match snapshot.get(px.x, px.y) {
+                // translucent pixels blend with whatever is below, we can't verify those
+                Some(value) if px.value[3] == 0xff => value != px.value,
+                _ => true,
+            })
+            .copied()
+            .collect()
+    }
+}
+
+impl ShadowRead<'_> {
+    /// The sampled color at canvas position `x`, `y`, `None` outside the sampled region.
+    pub fn get(&self, x: u32, y: u32) -> Option<Rgba<u8>> {
+        let region = &self.snapshot.region;
+        if x < region.origin_x
+            || y < region.origin_y
+            || x >= region.origin_x + region.size_x
+            || y >= region.origin_y + region.size_y
+        {
+            return None;
+        }
+
+        Some(*self.snapshot.image.get_pixel(x, y))
+    }
+}
+
+fn clamp(region: Area, (width, height): (u32, u32)) -> Area {
+    Area {
+        origin_x: region.origin_x.min(width),
+        origin_y: region.origin_y.min(height),
+        size_x: region.size_x.min(width.saturating_sub(region.origin_x)),
+        size_y: region.size_y.min(height.saturating_sub(region.origin_y)),
+    }
+}
+
+/// Every canvas coordinate in `region`, encoded as reads.
+fn encode_region(region: &Area) -> CommandBuffer {
+    let coords: Vec<(u32, u32)> = (region.origin_y..region.origin_y + region.size_y)
+        .flat_map(|y| (region.origin_x..region.origin_x + region.size_x).map(move |x| (x, y)))
+        .collect();
+    CommandBuffer::encode_reads(&coords, 0, 1, false)
+}
+
+async fn sample(
+    server: &str,
+    region: &mut watch::Receiver<Area>,
+    snapshot: &RwLock<Snapshot>,
+    generation: &watch::Sender<u64>,
+    interval: Duration,
+    limiter: &RateLimiter,
+) -> Result<()> {
+    let (mut rx, mut tx) = connect(server).await?;
+
+    let mut sampled = region.borrow_and_update().clone();
+    let mut queries = encode_region(&sampled);
+
+    // stop once nobody holds the shadow anymore
+    while !generation.is_closed() {
+        if region.has_changed()? {
+            sampled = region.borrow_and_update().clone();
+            queries = encode_region(&sampled);
+        }
+
+        let mut pixels = Vec::new();
+        read_pixels(&mut rx, &mut tx, &queries, &mut pixels, limiter).await?;
+
+        {
+            let mut snapshot = snapshot.write().unwrap();
+            snapshot.region = sampled.clone();
+            let image = &mut snapshot.image;
+            for px in pixels {
+                if px.x < image.width() && px.y < image.height() {
+                    image.put_pixel(px.x, px.y, px.value);
+                }
+            }
+        }
+        generation.send_modify(|generation| *generation += 1);
+
+        sleep(interval).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        limit::Limits,
+        server::{Server, BACKGROUND},
+    };
+
+    fn area(origin_x: u32, origin_y: u32) -> Area {
+        Area {
+            origin_x,
+            origin_y,
+            size_x: 4,
+            size_y: 4,
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_region() {
+        let server = Server::local(16, 16, false).await.unwrap();
+        let config = Config {
+            threads: 1,
+            image_area: area(0, 0),
+            ..server.config()
+        };
+
+        let limiter = Arc::new(RateLimiter::new(Limits::default()));
+        let mut shadow = Shadow::spawn(&config, area(0, 0), Duration::from_millis(10), limiter);
+        shadow.changed().await;
+        assert_eq!(shadow.read().unwrap().get(1, 1), Some(BACKGROUND));
+        assert_eq!(shadow.read().unwrap().get(10, 10), None);
+
+        shadow.set_region(area(8, 8));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while shadow.read().unwrap().get(10, 10).is_none() {
+                shadow.changed().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(shadow.read().unwrap().get(1, 1), None);
+    }
+}